@@ -1,69 +1,403 @@
 use std::{
     borrow::Cow,
-    collections::{HashSet},
-    env,
+    collections::{HashMap, HashSet, VecDeque},
+    env, fmt,
     fs::{self, File},
     path::{Path, PathBuf},
-    io::Read,
-    process::{ChildStdout, Command, Stdio},
+    io::{self, BufRead, PipeReader, PipeWriter, Read},
+    process::{Child, Command, ExitStatus, Stdio},
+    sync::RwLock,
 };
 
-use ansi_term::Colour::{Blue, Fixed, Green, Purple, Yellow};
-use std::ffi::CString;
-use std::os::unix::ffi::OsStrExt;
+use ansi_term::Colour::{Blue, Cyan, Fixed, Green, Purple, Red, Yellow};
+use std::ffi::{CStr, CString};
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::os::unix::fs::MetadataExt;
+use std::os::fd::{AsFd, OwnedFd};
 use std::os::unix::io::FromRawFd;
-use std::time::{SystemTime, UNIX_EPOCH};
-use libc::{self, F_GETFL, F_SETFL, O_NONBLOCK, O_RDONLY, O_WRONLY};
+use std::os::unix::process::CommandExt;
+use std::sync::atomic::{AtomicBool, AtomicI32, Ordering};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use libc::{self, O_NONBLOCK, O_RDONLY, O_WRONLY};
 use git2::Repository;
-use once_cell::sync::Lazy;
+use once_cell::sync::{Lazy, OnceCell};
 use rayon::prelude::*;
 use rustyline::{
     completion::{Completer, FilenameCompleter, Pair},
-    config::{Builder as ConfigBuilder, CompletionType, Config, EditMode},
+    config::{Builder as ConfigBuilder, CompletionType, Config, Configurer, EditMode},
     error::ReadlineError,
-    highlight::{Highlighter, MatchingBracketHighlighter},
+    highlight::{CmdKind, Highlighter, MatchingBracketHighlighter},
     hint::Hinter,
-    history::FileHistory,
-    validate::{MatchingBracketValidator, Validator},
+    history::{FileHistory, History},
+    validate::Validator,
     Context, Editor, Helper,
 };
 
-static BIN_CACHE: Lazy<Vec<String>> = Lazy::new(|| {
-    let mut bins = if let Some(path_var) = env::var_os("PATH") {
-        env::split_paths(&path_var)
-            .par_bridge()
-            .map(|dir| {
-                fs::read_dir(dir)
-                    .map(|entries| entries.filter_map(Result::ok).collect::<Vec<_>>())
-                    .unwrap_or_default()
-            })
-            .flatten()
-            .map(|e| e.path())
-            .filter(|p| p.is_file() && is_executable(p))
-            .filter_map(|p| p.file_name().and_then(|n| n.to_str().map(String::from)))
-            .collect::<HashSet<_>>()
-            .into_iter()
-            .collect()
-    } else {
-        Vec::new()
-    };
+// PATH が未設定・空の環境 (コンテナや一部の init など) でも最低限コマンドが見つかるようにする
+const DEFAULT_PATH: &str = "/usr/local/bin:/usr/bin:/bin";
+
+fn search_path() -> String {
+    env::var("PATH")
+        .ok()
+        .filter(|p| !p.is_empty())
+        .unwrap_or_else(|| DEFAULT_PATH.to_string())
+}
+
+// PATH の各ディレクトリ。POSIX と同じく空の要素 (`a::b` や先頭・末尾の `:`) は `.` とみなす。
+// 実行時の解決 (resolve_command_path) と補完 (BIN_CACHE) は必ずこれを通して同じ規則に揃える
+fn path_dirs() -> Vec<PathBuf> {
+    search_path()
+        .split(':')
+        .map(|dir| PathBuf::from(if dir.is_empty() { "." } else { dir }))
+        .collect()
+}
+
+// PATH 上のコマンド名。`.` のような相対パスの要素はカレントディレクトリで中身が変わるので
+// ここには含めず、補完のたびに読む (path_executables_in_cwd)。
+// 新しく入れたコマンドは `rehash` で読み直すまで補完に出ない
+static BIN_CACHE: Lazy<RwLock<Vec<String>>> = Lazy::new(|| RwLock::new(scan_path_binaries()));
+
+fn scan_path_binaries() -> Vec<String> {
+    let mut bins: Vec<String> = path_dirs()
+        .into_iter()
+        .filter(|dir| dir.is_absolute())
+        .par_bridge()
+        .map(|dir| {
+            fs::read_dir(dir)
+                .map(|entries| entries.filter_map(Result::ok).collect::<Vec<_>>())
+                .unwrap_or_default()
+        })
+        .flatten()
+        .map(|e| e.path())
+        .filter(|p| p.is_file() && is_executable(p))
+        .filter_map(|p| p.file_name().and_then(|n| n.to_str().map(String::from)))
+        .collect::<HashSet<_>>()
+        .into_iter()
+        .collect();
     bins.sort();
     bins
-});
+}
 
 #[derive(Debug, Default)]
 struct CommandInfo {
     args: Vec<String>,
-    stdin_path: Option<PathBuf>,
-    stdout_path: Option<(PathBuf, bool)>, // (path, is_append)
-    stderr_path: Option<PathBuf>,
+    stdin: InputSource,
+    stdout: OutputTarget,
+    stderr: OutputTarget,
+    subshell: bool, // `( ... )` を `unko -c` で実行する場合は引数を展開しない
+}
+
+// 標準入力の元。`< a < b` のように複数あれば bash と同じく最後のものを使う。
+// 2 段目以降ではヒアドキュメントだけが前の段からのパイプより優先する
+#[derive(Debug, Clone, Default, PartialEq)]
+enum InputSource {
+    #[default]
+    Inherit, // 前の段からのパイプか端末
+    File(PathBuf),
+    Text(String), // ヒアドキュメントやヒアストリングの本文 (展開前の単語)
+}
+
+// 標準出力・標準エラー出力の行き先
+#[derive(Debug, Clone, Default, PartialEq)]
+enum OutputTarget {
+    #[default]
+    Inherit, // 標準出力ならパイプか端末、標準エラー出力なら端末
+    File(PathBuf, WriteMode),
+    Other, // もう一方の、リダイレクトされる前の行き先 (`2>&1` や `>&2` による)
+}
+
+impl OutputTarget {
+    // `2>&1` (`>&2`) は、その時点での標準出力 (標準エラー出力) の行き先を複製する。
+    // そのため `>file 2>&1` と `2>&1 >file` では標準エラー出力の行き先が違う
+    fn duplicated(&self) -> OutputTarget {
+        match self {
+            OutputTarget::Inherit => OutputTarget::Other,
+            OutputTarget::File(path, mode) => OutputTarget::File(path.clone(), *mode),
+            OutputTarget::Other => OutputTarget::Inherit,
+        }
+    }
+}
+
+// 出力先のファイルの開き方
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum WriteMode {
+    Truncate, // `>`。noclobber なら既存のファイルには書かない
+    Clobber,  // `>|`。noclobber でも切り詰める
+    Append,   // `>>`
+}
+
+impl WriteMode {
+    fn of(op: &str) -> WriteMode {
+        if op.ends_with(">>") {
+            WriteMode::Append
+        } else if op.ends_with(">|") {
+            WriteMode::Clobber
+        } else {
+            WriteMode::Truncate
+        }
+    }
+
+    // noclobber のときの `>` は、既にある通常のファイルを開かない (`/dev/null` などはそのまま書ける)
+    fn open(self, path: &Path, noclobber: bool) -> io::Result<File> {
+        if self == WriteMode::Truncate && noclobber && fs::metadata(path).is_ok_and(|m| m.is_file()) {
+            return Err(io::Error::new(io::ErrorKind::AlreadyExists, "noclobber のため既存のファイルは上書きできません"));
+        }
+        fs::OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(self != WriteMode::Append)
+            .append(self == WriteMode::Append)
+            .open(path)
+    }
+}
+
+// リダイレクションの演算子 (パス名を取るものと fd の複製)
+const REDIRECT_OPS: &[&str] = &[
+    "<", "<<", "<<-", "<<<", ">", ">>", ">|", "2>", "2>>", "2>|", "&>", "&>>", "2>&1", ">&2",
+];
+
+// 構文エラー。pos は字句解析のエラーなら入力の文字位置、
+// parse_commands のエラーならトークンの位置 (どちらも 0 始まり)
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum ParseError {
+    UnclosedQuote { quote: char, pos: usize },
+    UnclosedBlock { open: char, pos: usize },
+    UnexpectedPipe { pos: usize },
+    MissingRedirectTarget { op: String, pos: usize },
+    RedirectionWithoutCommand { pos: usize },
+    UnclosedHeredoc { delimiter: String },
+    InputNotFirst { op: String, pos: usize },
+}
+
+impl ParseError {
+    // 続きを入力すれば解消するエラーか (Validator が入力を読み続ける)
+    fn is_incomplete(&self) -> bool {
+        matches!(
+            self,
+            ParseError::UnclosedQuote { .. }
+                | ParseError::UnclosedBlock { .. }
+                | ParseError::UnclosedHeredoc { .. }
+        )
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ParseError::UnclosedQuote { quote, pos } => write!(
+                f,
+                "構文エラー: クォーテーションが閉じられていません。({} 文字目の {quote})",
+                pos + 1
+            ),
+            ParseError::UnclosedBlock { open, pos } => write!(
+                f,
+                "構文エラー: 括弧が閉じられていません。({} 文字目の {open})",
+                pos + 1
+            ),
+            ParseError::UnexpectedPipe { .. } => {
+                write!(f, "構文エラー: パイプの前後にはコマンドが必要です。")
+            }
+            ParseError::MissingRedirectTarget { op, .. } => {
+                write!(f, "構文エラー: `{op}` の後にはファイル名が必要です。")
+            }
+            ParseError::RedirectionWithoutCommand { .. } => {
+                write!(f, "構文エラー: パイプラインの中のリダイレクションにはコマンドが必要です。")
+            }
+            ParseError::InputNotFirst { op, .. } => {
+                write!(f, "構文エラー: `{op}` はパイプラインの最初のコマンドでのみ使えます。")
+            }
+            ParseError::UnclosedHeredoc { delimiter } => {
+                write!(f, "構文エラー: ヒアドキュメントを終える `{delimiter}` の行がありません。")
+            }
+        }
+    }
+}
+
+// `set -o` / `set +o` で切り替えるシェルオプション
+#[derive(Debug, Default, Clone)]
+struct ShellOptions {
+    errexit: bool,
+    nounset: bool,
+    xtrace: bool,
+    pipefail: bool,
+    noclobber: bool,
+    noglob: bool,
+    nocaseglob: bool, // グロブの照合と並び順で大文字小文字を区別しない
+    dotglob: bool,    // `.` で始まる名前もグロブの `*` などに一致させる
+    strictutf8: bool, // コマンド置換の出力が UTF-8 として不正なら U+FFFD に置き換えずにエラーにする
+    vi: bool, // false なら emacs モード
+}
+
+impl ShellOptions {
+    const NAMES: [&'static str; 11] = [
+        "dotglob", "emacs", "errexit", "nocaseglob", "noclobber", "noglob", "nounset", "pipefail",
+        "strictutf8", "vi", "xtrace",
+    ];
+
+    // `set -f` のような一文字オプションに対応する名前
+    fn short_name(letter: char) -> Option<&'static str> {
+        match letter {
+            'C' => Some("noclobber"),
+            'e' => Some("errexit"),
+            'f' => Some("noglob"),
+            'u' => Some("nounset"),
+            'x' => Some("xtrace"),
+            _ => None,
+        }
+    }
+
+    fn get(&self, name: &str) -> Option<bool> {
+        Some(match name {
+            "dotglob" => self.dotglob,
+            "emacs" => !self.vi,
+            "errexit" => self.errexit,
+            "nocaseglob" => self.nocaseglob,
+            "noclobber" => self.noclobber,
+            "noglob" => self.noglob,
+            "nounset" => self.nounset,
+            "pipefail" => self.pipefail,
+            "strictutf8" => self.strictutf8,
+            "vi" => self.vi,
+            "xtrace" => self.xtrace,
+            _ => return None,
+        })
+    }
+
+    fn set(&mut self, name: &str, on: bool) -> bool {
+        match name {
+            "dotglob" => self.dotglob = on,
+            "emacs" => self.vi = !on,
+            "errexit" => self.errexit = on,
+            "nocaseglob" => self.nocaseglob = on,
+            "noclobber" => self.noclobber = on,
+            "noglob" => self.noglob = on,
+            "nounset" => self.nounset = on,
+            "pipefail" => self.pipefail = on,
+            "strictutf8" => self.strictutf8 = on,
+            "vi" => self.vi = on,
+            "xtrace" => self.xtrace = on,
+            _ => return false,
+        }
+        true
+    }
+}
+
+// プロセス内で共有するシェルの状態
+#[derive(Debug, Default)]
+struct ShellState {
+    options: ShellOptions,
+    hashed: HashMap<String, HashedCommand>,
+    hashed_for_path: String, // ハッシュ表を作ったときの PATH
+    history: Vec<HistoryEntry>,
+    aliases: HashMap<String, String>,
+    jobs: JobTable,
+    interactive: bool, // 対話モードならジョブ制御をする
+    errexit_tripped: bool,   // errexit で止まった。対話モードでは次のプロンプトまで残りを実行しない
+    dir_stack: Vec<PathBuf>, // pushd で積んだディレクトリ。末尾が `dirs` の 2 番目 (先頭はカレントディレクトリ)
+    loop_depth: usize,       // 実行中のループの入れ子の深さ。`break` / `continue` はこの段数までしか効かない
+    login: bool,             // ログインシェル。終了するときに ~/.unko_logout を読む
+}
+
+// 履歴の 1 項目。time は UNKO_HISTTIMEFORMAT が設定されているときだけ記録する UNIX 時刻
+#[derive(Debug, Clone)]
+struct HistoryEntry {
+    line: String,
+    time: Option<i64>,
+}
+
+#[derive(Debug)]
+struct HashedCommand {
+    path: String,
+    hits: u32,
+}
+
+impl ShellState {
+    // 直前と同じ行は重ねない (rustyline の history_ignore_dups と揃える)。UNKO_HISTSIZE を超えたら古いものから捨てる。
+    // 対話モードでは、シェルが強制終了されても残るよう一行ずつ履歴ファイルに追記する
+    fn add_history(&mut self, line: &str) {
+        if self.history.last().is_some_and(|e| e.line == line) {
+            return;
+        }
+        let time = env::var_os("UNKO_HISTTIMEFORMAT").map(|_| unsafe { libc::time(std::ptr::null_mut()) });
+        let entry = HistoryEntry { line: line.to_string(), time };
+        if self.interactive && history_size() > 0 {
+            let path = history_path();
+            if let Err(e) = append_history_file(&path, &entry) {
+                eprintln!("unko: {}: 履歴を保存できませんでした: {e}", path.display());
+            }
+        }
+        self.history.push(entry);
+        let excess = self.history.len().saturating_sub(history_size());
+        self.history.drain(..excess);
+    }
+
+    // 対話モードのシェルが終わるときに、追記で伸びた履歴ファイルを UNKO_HISTSIZE 件に切り詰める。
+    // 同時に動いている他のシェルが追記した分も残すよう、メモリ上の履歴ではなくファイルを読み直す
+    fn save_history(&self) {
+        let size = history_size();
+        if !self.interactive || size == 0 {
+            return;
+        }
+        let path = history_path();
+        let entries = load_history_file(&path);
+        if entries.len() <= size {
+            return;
+        }
+        if let Err(e) = write_history_file(&path, &entries[entries.len() - size..]) {
+            eprintln!("unko: {}: 履歴を保存できませんでした: {e}", path.display());
+        }
+    }
+
+    // PATH を毎回走査しないよう、解決済みのコマンドはハッシュ表から返す。
+    // PATH が変わっていたら表ごと作り直す
+    fn lookup_command(&mut self, cmd: &str) -> Option<String> {
+        if cmd.contains('/') {
+            return None;
+        }
+        let path_var = search_path();
+        if self.hashed_for_path != path_var {
+            self.hashed.clear();
+            self.hashed_for_path = path_var;
+        }
+        if let Some(entry) = self.hashed.get_mut(cmd)
+            && Path::new(&entry.path).is_file()
+        {
+            entry.hits += 1;
+            return Some(entry.path.clone());
+        }
+        let path = resolve_command_path(cmd)?;
+        // `.` から見つけたものはディレクトリを移ると別物になるので覚えない
+        if Path::new(&path).is_absolute() {
+            self.hashed.insert(cmd.to_string(), HashedCommand { path: path.clone(), hits: 1 });
+        }
+        Some(path)
+    }
 }
 
 struct ShellHelper {
     completer: FilenameCompleter,
     highlighter: MatchingBracketHighlighter,
-    validator: MatchingBracketValidator,
-    history: Vec<String>,
+    history: VecDeque<String>, // ヒント用の履歴。古いものから捨てる
+    aliases: HashSet<String>,  // ハイライト用のエイリアス名。コマンドを実行するたびに ShellState から写す
+}
+
+const DEFAULT_HINT_HISTORY_SIZE: usize = 10000;
+
+impl ShellHelper {
+    // ヒントに使う履歴の上限は UNKO_HINT_HISTSIZE で変えられる
+    fn remember(&mut self, line: &str) {
+        if self.history.back().is_some_and(|h| h == line) {
+            return;
+        }
+        let limit = env::var("UNKO_HINT_HISTSIZE")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(DEFAULT_HINT_HISTORY_SIZE);
+        self.history.push_back(line.to_string());
+        while self.history.len() > limit {
+            self.history.pop_front();
+        }
+    }
 }
 
 impl Helper for ShellHelper {}
@@ -79,36 +413,37 @@ impl Completer for ShellHelper {
     ) -> rustyline::Result<(usize, Vec<Pair>)> {
         let (start, word) = extract_current_token(line, pos);
 
-        if word.is_empty() {
-            let mut out = Vec::new();
-            for &b in ["echo", "ls", "cd", "pwd", "exit", "quit"].iter() {
-                out.push(Pair {
-                    display: b.into(),
-                    replacement: b.into(),
-                });
+        if let Some(dollar) = word.rfind('$') {
+            let (braced, prefix) = match word[dollar + 1..].strip_prefix('{') {
+                Some(prefix) => (true, prefix),
+                None => (false, &word[dollar + 1..]),
+            };
+            if prefix.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+                return Ok((start + dollar, complete_variable(prefix, braced)));
             }
-            return Ok((start, out));
+        }
+
+        if word.is_empty() {
+            return Ok((start, complete_builtin("")));
         }
 
         if !is_first_token(line, pos) {
-            return self.completer.complete(line, pos, ctx);
+            let (start, mut pairs) = self.completer.complete(line, pos, ctx)?;
+            // `cd` の引数はディレクトリだけ (FilenameCompleter はディレクトリに `/` を付ける)
+            if command_word_at(line, pos) == Some("cd") {
+                pairs.retain(|pair| pair.replacement.ends_with('/'));
+            }
+            return Ok((start, pairs));
         }
 
-        if word.contains('/') || word.starts_with('.') {
+        if word.contains('/') || word == "." || word == ".." {
             return self.completer.complete(line, pos, ctx);
         }
 
-        let mut out = Vec::new();
-        for &b in ["echo", "ls", "cd", "pwd", "exit", "quit"].iter() {
-            if b.starts_with(word) {
-                out.push(Pair {
-                    display: b.into(),
-                    replacement: b.into(),
-                });
-            }
-        }
-        for bin in BIN_CACHE.iter() {
-            if bin.starts_with(word) {
+        let mut out = complete_builtin(word);
+        let bins = BIN_CACHE.read().unwrap();
+        for bin in bins.iter().chain(&path_executables_in_cwd()) {
+            if completes_name(bin, word) {
                 out.push(Pair {
                     display: bin.clone(),
                     replacement: bin.clone(),
@@ -120,9 +455,71 @@ impl Completer for ShellHelper {
     }
 }
 
+// prefix で始まる組み込みコマンド (`:` や `.` のような記号とデバッグ用のものは除く)
+fn complete_builtin(prefix: &str) -> Vec<Pair> {
+    BUILTINS
+        .iter()
+        .filter(|b| !b.summary.is_empty() && b.name.starts_with(char::is_alphabetic) && b.name.starts_with(prefix))
+        .map(|b| Pair {
+            display: b.name.into(),
+            replacement: b.name.into(),
+        })
+        .collect()
+}
+
+// `$HO` や `${HO` の後ろで、環境変数の名前を補完する
+fn complete_variable(prefix: &str, braced: bool) -> Vec<Pair> {
+    let mut names: Vec<String> = env::vars_os()
+        .filter_map(|(name, _)| name.into_string().ok())
+        .filter(|name| name.starts_with(prefix))
+        .collect();
+    names.sort();
+    names
+        .into_iter()
+        .map(|name| Pair {
+            replacement: if braced { format!("${{{name}}}") } else { format!("${name}") },
+            display: name,
+        })
+        .collect()
+}
+
+// コマンド名の補完で name を prefix の候補にするか。グロブと同じく、`.` で始まる名前は
+// prefix も `.` で始まるときだけ候補にする
+fn completes_name(name: &str, prefix: &str) -> bool {
+    name.starts_with(prefix) && (prefix.starts_with('.') || !name.starts_with('.'))
+}
+
+// PATH に `.` (または空の要素) があるときだけ、カレントディレクトリの実行ファイルも
+// コマンド名として補完する
+fn path_executables_in_cwd() -> Vec<String> {
+    if !path_dirs().iter().any(|dir| dir == Path::new(".")) {
+        return Vec::new();
+    }
+    executables_in(Path::new("."))
+}
+
+// dir の実行ファイルのうち BIN_CACHE に無い名前
+fn executables_in(dir: &Path) -> Vec<String> {
+    let Ok(entries) = fs::read_dir(dir) else {
+        return Vec::new();
+    };
+    let bins = BIN_CACHE.read().unwrap();
+    entries
+        .filter_map(Result::ok)
+        .filter(|e| is_executable(&e.path()))
+        .filter_map(|e| e.file_name().into_string().ok())
+        .filter(|name| bins.binary_search(name).is_err())
+        .collect()
+}
+
 fn is_first_token(line: &str, pos: usize) -> bool {
     !line[..pos].contains(char::is_whitespace)
 }
+// pos のあるコマンドの最初の単語。`;` `&&` `||` `|` の後ろから数える
+fn command_word_at(line: &str, pos: usize) -> Option<&str> {
+    let start = line[..pos].rfind([';', '&', '|']).map_or(0, |i| i + 1);
+    line[start..pos].split_whitespace().next()
+}
 fn extract_current_token(line: &str, pos: usize) -> (usize, &str) {
     let start = line[..pos]
         .rfind(char::is_whitespace)
@@ -138,10 +535,10 @@ fn is_executable(path: &Path) -> bool {
         return false;
     }
     const BAD: &[&str] = &["dll", "exe", "com"];
-    if let Some(ext) = path.extension().and_then(|e| e.to_str()) {
-        if BAD.contains(&ext.to_ascii_lowercase().as_str()) {
-            return false;
-        }
+    if let Some(ext) = path.extension().and_then(|e| e.to_str())
+        && BAD.contains(&ext.to_ascii_lowercase().as_str())
+    {
+        return false;
     }
     true
 }
@@ -157,13 +554,97 @@ impl Hinter for ShellHelper {
     }
 }
 
+// `$` の直後から `NAME`・`{...}`・`?` などの特殊パラメータを読み、`$` ごとシアンで塗る。
+// 変数の参照でなければ (`$(` や単独の `$`) `$` だけをそのまま返す
+fn paint_variable(chars: &mut Chars<'_>) -> String {
+    let mut name = String::new();
+    if chars.next_if_eq(&'{').is_some() {
+        name.push('{');
+        for c in chars.by_ref() {
+            name.push(c);
+            if c == '}' {
+                break;
+            }
+        }
+    } else if let Some(c) = chars.next_if(|c| "?#@".contains(*c) || c.is_ascii_digit()) {
+        name.push(c);
+    } else {
+        while let Some(c) = chars.next_if(|c| c.is_ascii_alphanumeric() || *c == '_') {
+            name.push(c);
+        }
+    }
+    if name.is_empty() {
+        return "$".to_string();
+    }
+    Cyan.paint(format!("${name}")).to_string()
+}
+
+// シェルの予約語。`type` はこれを keyword と表示し、ハイライトではコマンド名として塗る
+const KEYWORDS: &[&str] = &[
+    "!", "((", "[[", "]]", "do", "done", "elif", "else", "fi", "for", "if", "in", "then", "until", "while",
+];
+
+// コマンド名の後ろでも次の単語がコマンド名になる予約語
+const KEYWORDS_BEFORE_COMMAND: &[&str] = &["if", "then", "elif", "else", "while", "until", "do", "!"];
+
+impl ShellHelper {
+    // コマンド名を、実行できるものなら緑、見つからなければ赤で塗る。
+    // PATH は毎回走査せず補完と同じ BIN_CACHE を引くので、新しく入れたコマンドは `rehash` するまで赤のまま。
+    // 変数やチルダを含む名前は展開してみないと分からないので塗らない
+    fn paint_command(&self, word: &str) -> String {
+        if word.contains(['$', '`', '\\']) || word.starts_with('~') {
+            return word.to_string();
+        }
+        let found = if word.contains('/') {
+            is_executable(Path::new(word))
+        } else {
+            self.aliases.contains(word)
+                || builtin_kind(word).is_some()
+                || KEYWORDS.contains(&word)
+                || BIN_CACHE.read().unwrap().binary_search(&word.to_string()).is_ok()
+        };
+        if found { Green.paint(word).to_string() } else { Red.paint(word).to_string() }
+    }
+}
+
 impl Highlighter for ShellHelper {
     fn highlight<'l>(&self, line: &'l str, _pos: usize) -> Cow<'l, str> {
         let mut out = String::with_capacity(line.len());
         let mut chars = line.chars().peekable();
+        // 次の単語がコマンド名の位置にあるか (行頭と `|` `;` `&` `(` の後、一部の予約語の後)
+        let mut at_command = true;
         while let Some(c) = chars.next() {
             match c {
+                '(' if chars.peek() == Some(&'(') => {
+                    chars.next();
+                    out.push_str("((");
+                    at_command = false;
+                }
+                '|' | ';' | '&' | '(' => {
+                    out.push(c);
+                    at_command = true;
+                }
+                '$' => {
+                    at_command = false;
+                    out.push_str(&paint_variable(&mut chars));
+                }
+                c if at_command && !c.is_whitespace() && !"'\"<>)".contains(c) => {
+                    let mut word = c.to_string();
+                    while let Some(n) = chars.next_if(|n| !n.is_whitespace() && !"|;&()<>'\"".contains(*n)) {
+                        word.push(n);
+                    }
+                    // 代入の後ろと一部の予約語の後ろは、まだコマンド名の位置
+                    let assignment = split_assignment(&word).is_some();
+                    at_command = assignment || KEYWORDS_BEFORE_COMMAND.contains(&word.as_str());
+                    if assignment {
+                        out.push_str(&word);
+                    } else {
+                        out.push_str(&self.paint_command(&word));
+                    }
+                }
+                c if c.is_whitespace() => out.push(c),
                 '\'' => {
+                    at_command = false;
                     out.push_str(&Yellow.paint("'").to_string());
                     while let Some(&n) = chars.peek() {
                         out.push_str(&Yellow.paint(n.to_string()).to_string());
@@ -174,31 +655,45 @@ impl Highlighter for ShellHelper {
                     }
                 }
                 '"' => {
+                    at_command = false;
                     out.push_str(&Purple.paint("\"").to_string());
-                    while let Some(&n) = chars.peek() {
+                    while let Some(n) = chars.next() {
+                        // ダブルクォートの中でも変数は展開されるので塗り分ける
+                        if n == '$' {
+                            out.push_str(&paint_variable(&mut chars));
+                            continue;
+                        }
                         out.push_str(&Purple.paint(n.to_string()).to_string());
-                        chars.next();
                         if n == '"' {
                             break;
                         }
                     }
                 }
                 '-' if out.ends_with(' ') || out.is_empty() => {
+                    at_command = false;
                     out.push_str(&Blue.paint("-").to_string());
                     while let Some(&n) = chars.peek() {
-                        if n.is_whitespace() {
+                        if n.is_whitespace() || "|;&()<>".contains(n) {
                             break;
                         }
                         out.push_str(&Blue.paint(n.to_string()).to_string());
                         chars.next();
                     }
                 }
-                _ => out.push(c),
+                _ => {
+                    out.push(c);
+                    at_command = false;
+                }
             }
         }
         Cow::Owned(out)
     }
 
+    // コマンド名の色は入力のたびに変わりうるので、カーソル移動以外では毎回塗り直す
+    fn highlight_char(&self, line: &str, pos: usize, kind: CmdKind) -> bool {
+        kind != CmdKind::MoveCursor || self.highlighter.highlight_char(line, pos, kind)
+    }
+
     fn highlight_prompt<'b, 's: 'b, 'p: 'b>(
         &'s self,
         prompt: &'p str,
@@ -213,82 +708,180 @@ impl Validator for ShellHelper {
         &self,
         ctx: &mut rustyline::validate::ValidationContext<'_>,
     ) -> rustyline::Result<rustyline::validate::ValidationResult> {
-        self.validator.validate(ctx)
+        if is_incomplete(ctx.input()) {
+            Ok(rustyline::validate::ValidationResult::Incomplete)
+        } else {
+            Ok(rustyline::validate::ValidationResult::Valid(None))
+        }
+    }
+}
+
+unsafe extern "C" {
+    fn tzset();
+}
+
+const WEEKDAYS: [&str; 7] = ["Sun", "Mon", "Tue", "Wed", "Thu", "Fri", "Sat"];
+const MONTHS: [&str; 12] = [
+    "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+];
+
+// chrono を使わず libc の localtime_r で現在のローカル時刻を得る (TZ も反映される)
+fn local_time() -> Option<libc::tm> {
+    local_time_at(unsafe { libc::time(std::ptr::null_mut()) })
+}
+
+fn local_time_at(time: libc::time_t) -> Option<libc::tm> {
+    unsafe {
+        tzset();
+        let mut tm: libc::tm = std::mem::zeroed();
+        if libc::localtime_r(&time, &mut tm).is_null() {
+            None
+        } else {
+            Some(tm)
+        }
     }
-    fn validate_while_typing(&self) -> bool {
-        self.validator.validate_while_typing()
+}
+
+fn hour_12(tm: &libc::tm) -> i32 {
+    match tm.tm_hour % 12 {
+        0 => 12,
+        h => h,
     }
 }
 
-fn build_prompt() -> String {
-    let user = env::var("USER").unwrap_or_default();
-    let cwd = env::current_dir().unwrap_or_default();
-    let path_display = if let Some(home) = dirs::home_dir() {
-        if let Ok(p) = cwd.strip_prefix(&home) {
+fn strftime(format: &str, tm: &libc::tm) -> String {
+    let Ok(format) = CString::new(format) else {
+        return String::new();
+    };
+    let mut buf = [0u8; 256];
+    let len = unsafe {
+        libc::strftime(buf.as_mut_ptr().cast(), buf.len(), format.as_ptr(), tm)
+    };
+    String::from_utf8_lossy(&buf[..len]).into_owned()
+}
+
+// `\t` `\T` `\@` `\d` の時刻系エスケープを展開する。対象外なら None
+fn expand_time_escape(esc: char, tm: &libc::tm) -> Option<String> {
+    match esc {
+        't' => Some(format!("{:02}:{:02}:{:02}", tm.tm_hour, tm.tm_min, tm.tm_sec)),
+        'T' => Some(format!("{:02}:{:02}:{:02}", hour_12(tm), tm.tm_min, tm.tm_sec)),
+        '@' => Some(format!(
+            "{:02}:{:02} {}",
+            hour_12(tm),
+            tm.tm_min,
+            if tm.tm_hour < 12 { "AM" } else { "PM" }
+        )),
+        'd' => Some(format!(
+            "{} {} {:02}",
+            WEEKDAYS.get(tm.tm_wday as usize).unwrap_or(&"???"),
+            MONTHS.get(tm.tm_mon as usize).unwrap_or(&"???"),
+            tm.tm_mday
+        )),
+        _ => None,
+    }
+}
+
+fn render_prompt(template: &str) -> String {
+    let tm = local_time();
+    let mut out = String::with_capacity(template.len());
+    let mut chars = template.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            out.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('\\') => out.push('\\'),
+            Some('u') => out.push_str(&Green.paint(env::var("USER").unwrap_or_default()).to_string()),
+            Some('w') => out.push_str(&Blue.paint(prompt_path()).to_string()),
+            Some('h') => out.push_str(&hostname()),
+            Some('g') => {
+                if let Some(branch) = git_branch() {
+                    out.push_str(&Purple.paint(format!("({branch})")).to_string());
+                }
+            }
+            Some('$') => out.push(if unsafe { libc::geteuid() } == 0 { '#' } else { '$' }),
+            Some(esc) => match tm.as_ref().and_then(|tm| expand_time_escape(esc, tm)) {
+                Some(s) => out.push_str(&s),
+                None => {
+                    // 知らないエスケープはそのまま残す
+                    out.push('\\');
+                    out.push(esc);
+                }
+            },
+            None => out.push('\\'),
+        }
+    }
+    out
+}
+
+// カレントディレクトリ。ホームの下なら `~` から書く
+fn prompt_path() -> String {
+    tilde_path(&env::current_dir().unwrap_or_default())
+}
+
+fn tilde_path(path: &Path) -> String {
+    if let Some(home) = dirs::home_dir() {
+        if let Ok(p) = path.strip_prefix(&home) {
             if p.as_os_str().is_empty() {
                 "~".to_string()
             } else {
                 format!("~/{}", p.display())
             }
         } else {
-            cwd.display().to_string()
+            path.display().to_string()
         }
     } else {
-        cwd.display().to_string()
+        path.display().to_string()
+    }
+}
+
+fn git_branch() -> Option<String> {
+    let cwd = env::current_dir().ok()?;
+    let repo = Repository::discover(cwd).ok()?;
+    let head = repo.head().ok()?;
+    head.shorthand().map(|s| s.to_owned())
+}
+
+// ホスト名の最初の `.` まで
+fn hostname() -> String {
+    let mut buf = [0u8; 256];
+    if unsafe { libc::gethostname(buf.as_mut_ptr() as *mut libc::c_char, buf.len()) } != 0 {
+        return String::new();
+    }
+    let len = buf.iter().position(|&b| b == 0).unwrap_or(buf.len());
+    let name = String::from_utf8_lossy(&buf[..len]);
+    name.split('.').next().unwrap_or_default().to_string()
+}
+
+// 直前のコマンドの終了ステータスは、成功なら緑の ✓、失敗なら赤の数字で `>` の前に出す。
+// UNKO_PROMPT_NO_STATUS を設定すると出さない
+fn build_prompt(last_status: i32) -> String {
+    if let Ok(template) = env::var("UNKO_PROMPT") {
+        return render_prompt(&template);
+    }
+    let user = env::var("USER").unwrap_or_default();
+    let git_str = match git_branch() {
+        Some(branch) => format!(" {}", Purple.paint(format!("({})", branch))),
+        None => String::new(),
     };
-    let branch = Repository::discover(&cwd)
-        .ok()
-        .and_then(|repo| {
-            repo.head()
-                .ok()
-                .and_then(|h| h.shorthand().map(|s| s.to_owned()))
-        })
-        .unwrap_or_default();
-    let git_str = if branch.is_empty() {
+    let status_str = if env::var_os("UNKO_PROMPT_NO_STATUS").is_some_and(|v| !v.is_empty()) {
         String::new()
+    } else if last_status == 0 {
+        format!(" {}", Green.paint("✓"))
     } else {
-        format!(" {}", Purple.paint(format!("({})", branch)))
+        format!(" {}", Red.paint(last_status.to_string()))
     };
     format!(
-        "{}:{}{}{} ",
+        "{}:{}{}{}{} ",
         Green.paint(user),
-        Blue.paint(path_display),
+        Blue.paint(prompt_path()),
         git_str,
+        status_str,
         Blue.paint(">"),
     )
 }
 
-fn expand_var<I: Iterator<Item = char>>(iter: &mut std::iter::Peekable<I>) -> String {
-    if let Some('{') = iter.peek().copied() {
-        iter.next();
-        let mut name = String::new();
-        while let Some(&c) = iter.peek() {
-            if c == '}' {
-                iter.next();
-                break;
-            }
-            name.push(c);
-            iter.next();
-        }
-        env::var(name).unwrap_or_default()
-    } else {
-        let mut name = String::new();
-        while let Some(&c) = iter.peek() {
-            if c.is_alphanumeric() || c == '_' {
-                name.push(c);
-                iter.next();
-            } else {
-                break;
-            }
-        }
-        if name.is_empty() {
-            "$".to_string()
-        } else {
-            env::var(name).unwrap_or_default()
-        }
-    }
-}
-
 fn mkfifo_temp() -> PathBuf {
     let mut path = std::env::temp_dir();
     let uniq = SystemTime::now()
@@ -305,652 +898,5463 @@ fn mkfifo_temp() -> PathBuf {
     path
 }
 
+// プロセス置換の子プロセスと、それとつなぐ FIFO。drop したときに FIFO を消すので、
+// 待ち終わったときだけでなく途中で抜けたりパニックしたりしても /tmp に残らない
+struct ProcessSub {
+    helper: Option<std::thread::JoinHandle<Option<Child>>>, // FIFO を開いてから子を起動するスレッド
+    fifo: PathBuf,
+    reads: bool, // `>(cmd)` なら子が FIFO から読む
+}
+
+impl ProcessSub {
+    fn wait(mut self) {
+        if let Some(helper) = self.helper.take()
+            && let Ok(Some(mut child)) = helper.join()
+        {
+            let _ = wait_child(&mut child);
+        }
+    }
+
+    // 置換された側のコマンドが終わった後で待つ。そのコマンドが FIFO を開かなかった (`echo <(cmd)` など) なら
+    // スレッドは open で止まったままなので、こちらで反対側を開いてすぐ閉じ、先に進めてから待つ。
+    // スレッドがまだ open に入っていないこともあるので、終わるまで繰り返す
+    fn finish(self) {
+        let flags = if self.reads { O_WRONLY } else { O_RDONLY };
+        let path = CString::new(self.fifo.as_os_str().as_bytes()).unwrap();
+        while self.helper.as_ref().is_some_and(|helper| !helper.is_finished()) {
+            unsafe {
+                let fd = libc::open(path.as_ptr(), flags | O_NONBLOCK | libc::O_CLOEXEC);
+                if fd >= 0 {
+                    libc::close(fd);
+                }
+            }
+            std::thread::sleep(Duration::from_millis(5));
+        }
+        self.wait();
+    }
+}
+
+impl Drop for ProcessSub {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.fifo);
+    }
+}
+
+// `<(cmd)` なら cmd の標準出力を、`>(cmd)` なら標準入力を FIFO につないで起動する。
+// FIFO はブロックするモードで開くので、置換された側のコマンドが反対側を開くまで待つ。
+// 先に非ブロックで開くと、相手がいないうちに読んで EOF になったり、書き込みが失敗して端末に出たりする。
+// open で止まってもシェルが進めるように、開いて起動するところはスレッドに任せる
 fn spawn_process_sub(
     cmd_str: &str,
-    fifo_path: &Path,
+    fifo_path: PathBuf,
     is_output_sub: bool,
-    children: &mut Vec<std::process::Child>,
+    children: &mut Vec<ProcessSub>,
 ) {
     let exe = env::current_exe()
         .unwrap_or_else(|_| PathBuf::from(env::args().next().unwrap_or_default()));
-    let mut child_cmd = Command::new(exe);
-    child_cmd.arg("-c").arg(cmd_str);
-
-    unsafe {
-        let c_path = CString::new(fifo_path.as_os_str().as_bytes()).unwrap();
-        if is_output_sub {
-            let fd = libc::open(c_path.as_ptr(), O_RDONLY | O_NONBLOCK);
-            if fd >= 0 {
-                let flags = libc::fcntl(fd, F_GETFL);
-                if flags >= 0 {
-                    libc::fcntl(fd, F_SETFL, flags & !O_NONBLOCK);
-                }
-                let f = File::from_raw_fd(fd);
-                child_cmd.stdin(Stdio::from(f));
-            }
-            child_cmd.stdout(Stdio::inherit());
+    let cmd_str = cmd_str.to_string();
+    let path = fifo_path.clone();
+    let helper = std::thread::spawn(move || {
+        let mut child_cmd = Command::new(exe);
+        child_cmd.arg("-c").arg(cmd_str);
+        let fifo = if is_output_sub {
+            File::open(&path)
         } else {
-            let fd = libc::open(c_path.as_ptr(), O_WRONLY | O_NONBLOCK);
-            if fd >= 0 {
-                let f = File::from_raw_fd(fd);
-                child_cmd.stdout(Stdio::from(f));
+            fs::OpenOptions::new().write(true).open(&path)
+        };
+        match fifo {
+            Ok(fifo) if is_output_sub => child_cmd.stdin(fifo),
+            Ok(fifo) => child_cmd.stdout(fifo),
+            Err(e) => {
+                eprintln!("unko: {}: プロセス置換の FIFO を開けませんでした: {e}", path.display());
+                return None;
             }
-            child_cmd.stdin(Stdio::inherit());
-        }
-        child_cmd.stderr(Stdio::inherit());
-    }
+        };
+        child_cmd.spawn().ok()
+    });
+    children.push(ProcessSub { helper: Some(helper), fifo: fifo_path, reads: is_output_sub });
+}
+// --------------------------------------------------
 
-    if let Ok(c) = child_cmd.spawn() {
-        children.push(c);
+// トークン列をパイプラインの段に分ける。括弧や `[[ ... ]]`、複合コマンドの中の `|` では分けない
+fn split_pipeline(tokens: &[String]) -> Vec<&[String]> {
+    let mut groups = Vec::new();
+    let mut start = 0;
+    for (i, (token, depth)) in tokens.iter().zip(nesting_depths(tokens)).enumerate() {
+        if token == "|" && depth == 0 {
+            groups.push(&tokens[start..i]);
+            start = i + 1;
+        }
     }
+    groups.push(&tokens[start..]);
+    groups
 }
-// --------------------------------------------------
 
-fn parse_commands(tokens: &[String]) -> Result<Vec<CommandInfo>, String> {
+fn parse_commands(tokens: &[String]) -> Result<Vec<CommandInfo>, ParseError> {
     let mut commands = Vec::new();
     if tokens.is_empty() {
         return Ok(commands);
     }
 
-    for group in tokens.split(|token| token == "|") {
+    let mut start = 0; // group の先頭のトークン位置
+    for group in split_pipeline(tokens) {
+        let group_start = start;
+        start += group.len() + 1;
         if group.is_empty() {
-            return Err("構文エラー: パイプの前後にはコマンドが必要です。".to_string());
+            return Err(ParseError::UnexpectedPipe { pos: group_start.min(tokens.len() - 1) });
         }
 
-        if group.first().map(|s| s.as_str()) == Some("(")
-            && group.last().map(|s| s.as_str()) == Some(")")
-        {
-            let inner = group[1..group.len() - 1].join(" ");
+        // `( ... )` と、`while read line; do ...; done` のような複合コマンドの段は `unko -c` で実行する
+        let inner = match (group.first().map(String::as_str), group.last().map(String::as_str)) {
+            (Some("("), Some(")")) => Some(group[1..group.len() - 1].join(" ")),
+            (Some(first), _) if COMPOUND_OPENERS.contains(&first) => Some(group.join(" ")),
+            _ => None,
+        };
+        if let Some(inner) = inner {
             let exe = env::current_exe()
                 .unwrap_or_else(|_| PathBuf::from(env::args().next().unwrap_or_default()));
-            let mut cmd_info = CommandInfo::default();
-            cmd_info.args = vec![exe.to_string_lossy().into_owned(), "-c".to_string(), inner];
-            commands.push(cmd_info);
+            commands.push(CommandInfo {
+                args: vec![exe.to_string_lossy().into_owned(), "-c".to_string(), inner],
+                subshell: true,
+                ..Default::default()
+            });
             continue;
         }
 
         let mut cmd_info = CommandInfo::default();
-        let mut it = group.iter();
-        while let Some(token) = it.next() {
-            match token.as_str() {
-                "<" => {
-                    if let Some(path) = it.next() {
-                        cmd_info.stdin_path = Some(PathBuf::from(path));
-                    } else {
-                        return Err("構文エラー: `<` の後にはファイル名が必要です。".to_string());
-                    }
+        let mut it = group.iter().enumerate();
+        while let Some((i, token)) = it.next() {
+            let op = token.as_str();
+            match op {
+                "2>&1" => {
+                    cmd_info.stderr = cmd_info.stdout.duplicated();
+                    continue;
                 }
-                ">" => {
-                    if let Some(path) = it.next() {
-                        cmd_info.stdout_path = Some((PathBuf::from(path), false));
-                    } else {
-                        return Err("構文エラー: `>` の後にはファイル名が必要です。".to_string());
-                    }
+                ">&2" => {
+                    cmd_info.stdout = cmd_info.stderr.duplicated();
+                    continue;
                 }
-                ">>" => {
-                    if let Some(path) = it.next() {
-                        cmd_info.stdout_path = Some((PathBuf::from(path), true));
-                    } else {
-                        return Err("構文エラー: `>>` の後にはファイル名が必要です。".to_string());
-                    }
-                }
-                "2>" => {
-                    if let Some(path) = it.next() {
-                        cmd_info.stderr_path = Some(PathBuf::from(path));
-                    } else {
-                        return Err("構文エラー: `2>` の後にはファイル名が必要です。".to_string());
-                    }
+                _ if !REDIRECT_OPS.contains(&op) => {
+                    cmd_info.args.push(token.clone());
+                    continue;
                 }
+                _ => {}
+            }
+            let Some((_, path)) = it.next() else {
+                return Err(ParseError::MissingRedirectTarget {
+                    op: op.to_string(),
+                    pos: group_start + i,
+                });
+            };
+            // 2 段目以降の標準入力は前の段からのパイプなので、ファイルやヒアストリングは読ませない
+            if group_start > 0 && (op == "<" || op == "<<<") {
+                return Err(ParseError::InputNotFirst { op: op.to_string(), pos: group_start + i });
+            }
+            // ヒアストリングは単語の後に改行を一つ足したもの
+            if op == "<<<" {
+                cmd_info.stdin = InputSource::Text(format!("{path}\n"));
+                continue;
+            }
+            if op.starts_with("<<") {
+                cmd_info.stdin = InputSource::Text(path.clone());
+                continue;
+            }
+            let path = PathBuf::from(path);
+            match op {
+                "<" => cmd_info.stdin = InputSource::File(path),
+                ">" | ">>" | ">|" => cmd_info.stdout = OutputTarget::File(path, WriteMode::of(op)),
+                "2>" | "2>>" | "2>|" => cmd_info.stderr = OutputTarget::File(path, WriteMode::of(op)),
+                // `&>file` は `>file 2>&1` と同じ
                 _ => {
-                    cmd_info.args.push(token.clone());
+                    cmd_info.stdout = OutputTarget::File(path, WriteMode::of(op));
+                    cmd_info.stderr = cmd_info.stdout.clone();
                 }
             }
         }
+        // `> out` だけの行は bash と同じく `:` として扱い、ファイルの作成や切り詰めだけを行う
         if cmd_info.args.is_empty() {
-            return Err("構文エラー: 実行するコマンドがありません。".to_string());
+            if tokens.len() > group.len() {
+                return Err(ParseError::RedirectionWithoutCommand { pos: group_start });
+            }
+            cmd_info.args.push(":".to_string());
         }
         commands.push(cmd_info);
     }
     Ok(commands)
 }
 
-fn run_pipeline(commands: Vec<CommandInfo>) -> i32 {
-    if commands.is_empty() {
-        return 0;
-    }
+// `NAME=value` の形の単語を名前と (展開前の) 値に分ける
+fn split_assignment(word: &str) -> Option<(&str, &str)> {
+    let (name, value) = word.split_once('=')?;
+    is_valid_name(name).then_some((name, value))
+}
 
-    let last_idx = commands.len() - 1;
-    let mut previous_stdout: Option<ChildStdout> = None;
-    let mut children = Vec::new();
+// 変数名は `[A-Za-z_][A-Za-z0-9_]*`
+fn is_valid_name(name: &str) -> bool {
+    name.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_')
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
 
-    for (idx, mut cmd_info) in commands.into_iter().enumerate() {
-        if cmd_info.args.is_empty() {
-            eprintln!("エラー: パイプラインに空のコマンドが含まれています。");
-            return 1;
+// 実行しないコマンドのリダイレクション先を開くだけ開く。出力先は作成 (`>` なら切り詰め) される
+fn open_null_redirections(cmd_info: &CommandInfo, noclobber: bool) -> bool {
+    if let InputSource::File(path) = &cmd_info.stdin {
+        let path = expand_path(path);
+        if let Err(e) = File::open(&path) {
+            eprintln!("入力ファイル '{}' を開けませんでした: {}", path.display(), e);
+            return false;
         }
-
-        if let Some(p) = resolve_command_path(&cmd_info.args[0]) {
-            cmd_info.args[0] = p;
+    }
+    for target in [&cmd_info.stdout, &cmd_info.stderr] {
+        let OutputTarget::File(path, mode) = target else { continue };
+        let path = expand_path(path);
+        if let Err(e) = mode.open(&path, noclobber) {
+            eprintln!("出力ファイル '{}' を開けませんでした: {}", path.display(), e);
+            return false;
         }
+    }
+    true
+}
 
-        let mut expanded_args: Vec<String> = if cmd_info
-            .args
-            .get(1)
-            .map(|s| s == "-c")
-            .unwrap_or(false)
-        {
-            cmd_info
-                .args
-                .iter()
-                .enumerate()
-                .map(|(i, a)| if i <= 1 { expand_vars(a) } else { a.clone() })
-                .collect()
-        } else {
-            cmd_info.args.iter().map(|a| expand_vars(a)).collect()
-        };
-
-        let mut extra_children = Vec::new();
-        for arg in expanded_args.iter_mut() {
-            if let Some(rest) = arg.strip_prefix(">(").and_then(|s| s.strip_suffix(')')) {
-                let fifo = mkfifo_temp();
-                spawn_process_sub(rest.trim(), &fifo, true, &mut extra_children);
-                *arg = fifo.to_string_lossy().into_owned();
-            } else if let Some(rest) = arg.strip_prefix("<(").and_then(|s| s.strip_suffix(')')) {
-                let fifo = mkfifo_temp();
-                spawn_process_sub(rest.trim(), &fifo, false, &mut extra_children);
-                *arg = fifo.to_string_lossy().into_owned();
-            }
+// 改行までを 1 バイトずつ読む。上流の出力を全部溜め込まず、改行より後ろも読み進めない。
+// 変数に入れられない NUL は捨てる。改行まで読めたかどうかも返す (偽なら入力の終わりに達した)
+fn read_line_unbuffered(reader: &mut impl Read) -> (String, bool) {
+    let mut line = Vec::new();
+    let mut byte = [0u8; 1];
+    let newline = loop {
+        match reader.read(&mut byte) {
+            Ok(0) => break false,
+            Ok(_) if byte[0] == b'\n' => break true,
+            Ok(_) if byte[0] == 0 => {}
+            Ok(_) => line.push(byte[0]),
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(_) => break false,
         }
-        // 追加子プロセスを main の children にマージ
-        children.extend(extra_children);
-        // --------------------------------------
+    };
+    (String::from_utf8_lossy(&line).into_owned(), newline)
+}
 
-        if expanded_args[0] == "read" {
-            if let Some(var) = expanded_args.get(1) {
-                let mut input = String::new();
-                if let Some(mut stdin_pipe) = previous_stdout.take() {
-                    stdin_pipe.read_to_string(&mut input).ok();
-                } else {
-                    std::io::stdin().read_to_string(&mut input).ok();
-                }
-                if let Some(pos) = input.find('\n') {
-                    input.truncate(pos);
-                }
-                unsafe { env::set_var(var, input.trim_end_matches('\n')); }
-                previous_stdout = None;
+// `read [-r] [-p PROMPT] [NAME...]`。一行読んで IFS で区切り、NAME に順に入れる (最後の NAME には残り全部)。
+// NAME を省くと REPLY に入れる。`-r` が無ければバックスラッシュの付いた文字はそのまま (区切りにもしない) 扱い、
+// 行末の `\` で次の行に続ける。プロンプトは端末から読むときだけ標準エラー出力に出す。
+// 入力の終わりに達したら、読めた分を入れて 1 を返す
+fn builtin_read(argv: &[String], input: &mut impl Read, from_terminal: bool) -> i32 {
+    let mut raw = false;
+    let mut prompt = None;
+    let mut args = argv[1..].iter();
+    let mut names = Vec::new();
+    while let Some(arg) = args.next() {
+        let flags = match arg.strip_prefix('-') {
+            Some(flags) if names.is_empty() && !flags.is_empty() => flags,
+            _ => {
+                names.push(arg.as_str());
                 continue;
             }
+        };
+        for c in flags.chars() {
+            match c {
+                'r' => raw = true,
+                'p' => match args.next() {
+                    Some(p) => prompt = Some(p.as_str()),
+                    None => {
+                        eprintln!("read: -p には引数が必要です");
+                        return 2;
+                    }
+                },
+                _ => {
+                    eprintln!("read: -{c}: 不正なオプションです");
+                    eprintln!("read: 使い方: read [-r] [-p PROMPT] [NAME...]");
+                    return 2;
+                }
+            }
         }
+    }
+    if names.is_empty() {
+        names.push("REPLY");
+    }
+    if let Some(name) = names.iter().find(|name| !is_valid_name(name)) {
+        eprintln!("read: `{name}': 有効な識別子ではありません");
+        return 1;
+    }
 
-        if let Some(p) = resolve_command_path(&expanded_args[0]) {
-            expanded_args[0] = p;
+    if from_terminal && let Some(prompt) = prompt {
+        eprint!("{prompt}");
+    }
+    // 読んだ文字と、それがバックスラッシュで守られているか
+    let mut chars: Vec<(char, bool)> = Vec::new();
+    let newline = loop {
+        let (line, newline) = read_line_unbuffered(input);
+        if raw {
+            chars.extend(line.chars().map(|c| (c, false)));
+            break newline;
         }
-
-        let mut cmd = Command::new(&expanded_args[0]);
-        cmd.args(&expanded_args[1..]);
-
-        if let Some(stdin_pipe) = previous_stdout.take() {
-            cmd.stdin(Stdio::from(stdin_pipe));
-        } else if let Some(path) = cmd_info.stdin_path {
-            match File::open(&path) {
-                Ok(file) => {
-                    cmd.stdin(Stdio::from(file));
-                }
-                Err(e) => {
-                    eprintln!("入力ファイル '{}' を開けませんでした: {}", path.display(), e);
-                    return 1;
-                }
-            }
-        } else {
-            cmd.stdin(Stdio::inherit());
-        }
-
-        if idx == last_idx {
-            if let Some((path, append)) = cmd_info.stdout_path {
-                match fs::OpenOptions::new()
-                    .create(true)
-                    .write(true)
-                    .truncate(!append)
-                    .append(append)
-                    .open(&path)
-                {
-                    Ok(file) => {
-                        cmd.stdout(Stdio::from(file));
-                    }
-                    Err(e) => {
-                        eprintln!("出力ファイル '{}' を開けませんでした: {}", path.display(), e);
-                        return 1;
-                    }
-                }
+        let mut it = line.chars();
+        let mut continued = false;
+        while let Some(c) = it.next() {
+            if c != '\\' {
+                chars.push((c, false));
+            } else if let Some(next) = it.next() {
+                chars.push((next, true));
             } else {
-                cmd.stdout(Stdio::inherit());
+                continued = newline;
             }
-        } else {
-            if cmd_info.stdout_path.is_some() {
-                eprintln!("エラー: 出力リダイレクションはパイプラインの最後のコマンドでのみ許可されています。");
-                return 1;
-            }
-            cmd.stdout(Stdio::piped());
         }
+        if !continued {
+            break newline;
+        }
+    };
+    let fields = split_read_fields(&chars, names.len());
+    for (name, value) in names.iter().zip(fields) {
+        unsafe { env::set_var(name, value) };
+    }
+    if newline { 0 } else { 1 }
+}
 
-        if let Some(path) = cmd_info.stderr_path {
-            match fs::OpenOptions::new()
-                .create(true)
-                .write(true)
-                .truncate(true)
-                .open(&path)
-            {
-                Ok(file) => {
-                    cmd.stderr(Stdio::from(file));
-                }
-                Err(e) => {
-                    eprintln!("エラー出力ファイル '{}' を開けませんでした: {}", path.display(), e);
-                    return 1;
-                }
-            }
-        } else {
-            cmd.stderr(Stdio::inherit());
+// read が読んだ行を IFS で count 個に区切る。IFS の空白は前後を捨てて並びを一つの区切りとし、
+// 空白以外の IFS の文字は一文字ごとに区切る。最後の一つには残りを (後ろの IFS の空白だけ除いて) そのまま入れる。
+// バックスラッシュで守られた文字では区切らない
+fn split_read_fields(chars: &[(char, bool)], count: usize) -> Vec<String> {
+    let ifs = env::var("IFS").unwrap_or_else(|_| " \t\n".to_string());
+    let is_sep = |&(c, escaped): &(char, bool)| !escaped && ifs.contains(c);
+    let is_space = |ch: &(char, bool)| is_sep(ch) && " \t\n".contains(ch.0);
+    let skip_spaces = |mut i: usize| {
+        while i < chars.len() && is_space(&chars[i]) {
+            i += 1;
         }
+        i
+    };
 
-        match cmd.spawn() {
-            Ok(mut child) => {
-                previous_stdout = if idx != last_idx {
-                    child.stdout.take()
-                } else {
-                    None
-                };
-                children.push(child);
-            }
-            Err(e) => {
-                eprintln!("コマンド実行失敗: {}: {}", expanded_args[0], e);
-                return 1;
-            }
+    let mut fields = Vec::with_capacity(count);
+    let mut i = skip_spaces(0);
+    while fields.len() + 1 < count && i < chars.len() {
+        let start = i;
+        while i < chars.len() && !is_sep(&chars[i]) {
+            i += 1;
+        }
+        fields.push(chars[start..i].iter().map(|&(c, _)| c).collect::<String>());
+        i = skip_spaces(i);
+        if i < chars.len() && is_sep(&chars[i]) {
+            i = skip_spaces(i + 1);
         }
     }
+    let mut end = chars.len();
+    while end > i && is_space(&chars[end - 1]) {
+        end -= 1;
+    }
+    fields.push(chars[i..end].iter().map(|&(c, _)| c).collect());
+    fields.resize(count, String::new());
+    fields
+}
 
-    let mut last_status = 0;
-    for mut child in children {
+// wait がシグナルで中断されても (EINTR) 失敗扱いにせず待ち直す
+fn wait_child(child: &mut Child) -> io::Result<ExitStatus> {
+    loop {
         match child.wait() {
-            Ok(status) => last_status = status.code().unwrap_or(1),
-            Err(_) => last_status = 1,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            result => return result,
         }
     }
-    last_status
 }
 
-fn resolve_command_path(cmd: &str) -> Option<String> {
-    if cmd.contains('/') {
-        return None;
+// シグナルで終了したプロセスは終了コードを持たないので、慣例どおり 128 + シグナル番号にする
+fn exit_code(status: ExitStatus) -> i32 {
+    use std::os::unix::process::ExitStatusExt;
+    status
+        .code()
+        .or_else(|| status.signal().map(|sig| 128 + sig))
+        .unwrap_or(1)
+}
+
+// パイプラインの一つの段。組み込みコマンドや起動に失敗した段は Finished になる
+enum Stage {
+    Running(Child),
+    Finished(i32),
+}
+
+// 全ての段を起動してから待つ。途中の段が失敗しても残りの段は実行し、
+// 終了ステータスは最後の段のものを返す
+fn run_pipeline(commands: Vec<CommandInfo>, command: String, state: &mut ShellState) -> i32 {
+    let group = if state.interactive { ProcessGroup::Foreground(0) } else { ProcessGroup::Shell };
+    let spawned = spawn_pipeline(commands, state, group);
+    if state.interactive && !spawned.children.is_empty() {
+        return wait_foreground(spawned, command, state);
     }
-    let path_var = env::var("PATH").ok()?;
-    for dir in path_var.split(':') {
-        if dir.is_empty() {
-            continue;
-        }
-        let candidate = Path::new(dir).join(cmd);
-        if candidate.is_file() {
-            return Some(candidate.to_string_lossy().into_owned());
-        }
+
+    // 段はすべて並行して動いているので、どの順に待っても詰まらない。
+    // プロセス置換の子はパイプラインの段が終わって FIFO が閉じられてから待つ
+    let statuses: Vec<i32> = spawned
+        .children
+        .into_iter()
+        .map(|mut child| wait_child(&mut child).map(exit_code).unwrap_or(1))
+        .collect();
+    for helper in spawned.helpers {
+        helper.finish();
     }
-    None
+    pipeline_status(&spawned.stages, &statuses, state.options.pipefail)
 }
 
-fn try_builtin_special(argv: &[String]) -> bool {
-    match argv.first().map(String::as_str) {
-        Some("cd") => {
-            if let Some(path) = argv.get(1).map(String::as_str) {
-                if let Err(e) = env::set_current_dir(path) {
-                    eprintln!("cd: {e}");
-                }
-            } else {
-                let home = dirs::home_dir().unwrap_or_else(|| {
-                    eprintln!("cd: (ホームディレクトリが分から)ないです");
-                    env::current_dir().unwrap_or_else(|_| PathBuf::from("."))
-                });
-                if let Err(e) = env::set_current_dir(home) {
-                    eprintln!("cd: {e}");
-                }
-            }
-            true
-        }
-        Some("exit") | Some("quit") => {
-            let code = argv.get(1).and_then(|s| s.parse::<i32>().ok()).unwrap_or(0);
-            std::process::exit(code);
-        }
-        _ => false,
+// 起動したパイプライン。stages は段ごとのステータスで、子プロセスとして起動した段は None
+struct SpawnedPipeline {
+    children: Vec<Child>,
+    helpers: Vec<ProcessSub>, // プロセス置換のための子プロセス
+    stages: Vec<Option<i32>>,
+}
+
+// パイプライン全体のステータス。child_statuses は起動した子の終了ステータスで、stages の None を順に埋める。
+// 最後の段のステータスを返すが、pipefail なら 0 以外で終わった一番右の段のものを返す
+fn pipeline_status(stages: &[Option<i32>], child_statuses: &[i32], pipefail: bool) -> i32 {
+    let mut children = child_statuses.iter().copied();
+    let statuses: Vec<i32> = stages
+        .iter()
+        .map(|stage| stage.unwrap_or_else(|| children.next().unwrap_or(1)))
+        .collect();
+    if pipefail {
+        statuses.into_iter().rev().find(|&status| status != 0).unwrap_or(0)
+    } else {
+        statuses.last().copied().unwrap_or(0)
     }
 }
 
-fn parse_line(input: &str) -> Result<Vec<String>, String> {
-    enum State {
-        Normal,
-        Single,
-        Double,
+// パイプラインの段を入れるプロセスグループ。pgid が 0 なら最初の段を先頭に新しく作る
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum ProcessGroup {
+    Shell, // シェルと同じグループ (非対話モード)
+    Foreground(libc::pid_t), // 端末を渡して前面で動かす
+    Background(libc::pid_t),
+}
+
+fn spawn_pipeline(commands: Vec<CommandInfo>, state: &mut ShellState, mut group: ProcessGroup) -> SpawnedPipeline {
+    let mut spawned = SpawnedPipeline { children: Vec::new(), helpers: Vec::new(), stages: Vec::new() };
+    if commands.is_empty() {
+        return spawned;
     }
 
-    let mut state = State::Normal;
-    let mut tokens: Vec<String> = Vec::new();
-    let mut current = String::new();
-    let mut chars = input.chars().peekable();
+    let last_idx = commands.len() - 1;
+    let mut previous_stdout: Option<PipeReader> = None;
 
-    while let Some(c) = chars.next() {
-        match state {
-            State::Normal => match c {
-                ' ' | '\t' | '\n' => {
-                    if !current.is_empty() {
-                        tokens.push(std::mem::take(&mut current));
-                    }
-                }
-                // --- 修正点 ---
-                // クォート文字を current に追加しない
-                '\'' => {
-                    if !current.is_empty() {
-                        tokens.push(std::mem::take(&mut current));
-                    }
-                    state = State::Single;
-                }
-                // --- 修正点 ---
-                // クォート文字を current に追加しない
-                '"' => {
-                    if !current.is_empty() {
-                        tokens.push(std::mem::take(&mut current));
-                    }
-                    state = State::Double;
-                }
-                '\\' => {
-                    if let Some(n) = chars.next() {
-                        current.push(n);
-                    }
-                }
-                '$' => current.push('$'),
-                '>' | '<' if chars.peek() == Some(&'(') => {
-                    let mut token = String::from(c); // '>' もしくは '<'
-                    token.push(chars.next().unwrap()); // '('
-                    let mut depth = 1;
-                    while let Some(ch) = chars.next() {
-                        token.push(ch);
-                        if ch == '(' {
-                            depth += 1;
-                        } else if ch == ')' {
-                            depth -= 1;
-                            if depth == 0 {
-                                break;
-                            }
-                        }
-                    }
-                    tokens.push(token);
-                }
-                '|' | '<' => {
-                    if !current.is_empty() {
-                        tokens.push(std::mem::take(&mut current));
-                    }
-                    tokens.push(c.to_string());
-                }
-                '(' | ')' | ';' => {
-                    if !current.is_empty() {
-                        tokens.push(std::mem::take(&mut current));
-                    }
-                    tokens.push(c.to_string());
-                }
-                '>' => {
-                    if !current.is_empty() {
-                        tokens.push(std::mem::take(&mut current));
-                    }
-                    if chars.peek() == Some(&'>') {
-                        chars.next();
-                        tokens.push(">>".to_string());
-                    } else {
-                        tokens.push(">".to_string());
-                    }
-                }
-                '2' if chars.peek() == Some(&'>') => {
-                    if !current.is_empty() {
-                        tokens.push(std::mem::take(&mut current));
-                    }
-                    chars.next(); // consume '>'
-                    tokens.push("2>".to_string());
+    for (idx, cmd_info) in commands.into_iter().enumerate() {
+        let is_last = idx == last_idx;
+        let stdin = previous_stdout.take();
+        let stdout = if is_last {
+            None
+        } else {
+            match io::pipe() {
+                Ok((reader, writer)) => {
+                    previous_stdout = Some(reader);
+                    Some(writer)
                 }
-                _ => current.push(c),
-            },
-            State::Single => {
-                // --- 修正点 ---
-                // 終了クォートを見つけたらトークンを確定し、状態を戻す
-                // 終了クォート自体は含めない
-                if c == '\'' {
-                    tokens.push(std::mem::take(&mut current));
-                    state = State::Normal;
-                } else {
-                    current.push(c);
+                Err(e) => {
+                    eprintln!("エラー: パイプを作成できませんでした: {e}");
+                    spawned.stages.push(Some(1));
+                    break;
                 }
             }
-            State::Double => {
-                match c {
-                    '\\' => {
-                        if let Some(n) = chars.next() {
-                            current.push(n);
-                        }
-                    }
-                    '$' => current.push('$'),
-                    // --- 修正点 ---
-                    // 終了クォートを見つけたらトークンを確定し、状態を戻す
-                    // 終了クォート自体は含めない
-                    '"' => {
-                        tokens.push(std::mem::take(&mut current));
-                        state = State::Normal;
+        };
+        match run_stage(cmd_info, stdin, stdout, group, state, &mut spawned.helpers) {
+            Stage::Running(child) => {
+                let pid = child.id() as libc::pid_t;
+                match group {
+                    ProcessGroup::Foreground(0) => {
+                        // 標準入力がリダイレクトされていて子が自分で端末を取らなかった場合のため、
+                        // 次の段を起動する前にこちらからも渡しておく
+                        set_terminal_pgrp(pid);
+                        group = ProcessGroup::Foreground(pid);
                     }
-                    _ => current.push(c),
+                    ProcessGroup::Background(0) => group = ProcessGroup::Background(pid),
+                    _ => {}
                 }
+                spawned.children.push(child);
+                spawned.stages.push(None);
             }
+            Stage::Finished(status) => spawned.stages.push(Some(status)),
         }
     }
+    spawned
+}
 
-    // クォートが閉じられていない場合のエラーハンドリング
-    if !matches!(state, State::Normal) {
-        return Err("構文エラー: クォーテーションが閉じられていません。".to_string());
-    }
+// xtrace の表示。展開後の引数を `+ ` に続けて標準エラー出力に書く
+fn trace_command(words: &[String]) {
+    let line: Vec<Cow<str>> = words.iter().map(|w| shell_escape::unix::escape(w.into())).collect();
+    eprintln!("+ {}", line.join(" "));
+}
 
-    if !current.is_empty() {
-        tokens.push(std::mem::take(&mut current));
+// previous_stdout は前の段の出力を読むパイプ、stdout_pipe は次の段へ書き込むパイプ。
+// 段が終わるか起動に失敗すると書き込み側が閉じられ、次の段は EOF を読む。
+fn run_stage(
+    cmd_info: CommandInfo,
+    previous_stdout: Option<PipeReader>,
+    stdout_pipe: Option<PipeWriter>,
+    group: ProcessGroup,
+    state: &mut ShellState,
+    helpers: &mut Vec<ProcessSub>,
+) -> Stage {
+    if cmd_info.args.is_empty() {
+        eprintln!("エラー: パイプラインに空のコマンドが含まれています。");
+        return Stage::Finished(1);
     }
 
-    let home = env::var("HOME").unwrap_or_default();
-    for t in tokens.iter_mut() {
-        if t.starts_with('~') && (t.len() == 1 || t.as_bytes()[1] == b'/') {
-            let rest = &t[1..];
-            *t = format!("{}{}", home, rest);
+    // 先頭の `NAME=value` は代入。コマンドが続けばそのコマンドの環境にだけ渡し、
+    // 代入だけならシェルの変数 (環境変数) を左から順に設定する
+    let assignments: Vec<(&str, &str)> = if cmd_info.subshell {
+        Vec::new()
+    } else {
+        cmd_info.args.iter().map_while(|a| split_assignment(a)).collect()
+    };
+    let args = &cmd_info.args[assignments.len()..];
+    if args.is_empty() {
+        for (name, value) in assignments {
+            let value = expand_word(value);
+            if UNSET_EXPANDED.swap(false, Ordering::SeqCst) {
+                return Stage::Finished(1);
+            }
+            if state.options.xtrace {
+                trace_command(&[format!("{name}={value}")]);
+            }
+            unsafe { env::set_var(name, value) };
         }
+        return Stage::Finished(if open_null_redirections(&cmd_info, state.options.noclobber) { 0 } else { 1 });
     }
-    Ok(tokens)
-}
+    let assignments: Vec<(&str, String)> =
+        assignments.into_iter().map(|(name, value)| (name, expand_word(value))).collect();
 
-fn expand_vars(input: &str) -> String {
-    let mut out = String::new();
-    let mut chars = input.chars().peekable();
-    while let Some(c) = chars.next() {
-        if c == '$' {
-            if let Some(&'{') = chars.peek() {
-                chars.next();
-                let mut name = String::new();
-                while let Some(&ch) = chars.peek() {
-                    chars.next();
-                    if ch == '}' {
-                        break;
-                    }
-                    name.push(ch);
-                }
-                out.push_str(&env::var(name).unwrap_or_default());
+    let mut expanded_args: Vec<String> = if cmd_info.subshell {
+        cmd_info.args.clone()
+    } else {
+        let mut expanded = Vec::with_capacity(args.len());
+        for arg in args {
+            if let Some(rest) = arg.strip_prefix(">(").and_then(|s| s.strip_suffix(')')) {
+                let fifo = mkfifo_temp();
+                expanded.push(fifo.to_string_lossy().into_owned());
+                spawn_process_sub(rest.trim(), fifo, true, helpers);
+            } else if let Some(rest) = arg.strip_prefix("<(").and_then(|s| s.strip_suffix(')')) {
+                let fifo = mkfifo_temp();
+                expanded.push(fifo.to_string_lossy().into_owned());
+                spawn_process_sub(rest.trim(), fifo, false, helpers);
             } else {
-                let mut name = String::new();
-                while let Some(&ch) = chars.peek() {
-                    if ch.is_alphanumeric() || ch == '_' {
-                        name.push(ch);
-                        chars.next();
-                    } else {
-                        break;
-                    }
-                }
-                if name.is_empty() {
-                    out.push('$');
-                } else {
-                    out.push_str(&env::var(name).unwrap_or_default());
-                }
+                expanded.extend(expand_arg(arg, &state.options));
             }
-        } else {
-            out.push(c);
         }
+        expanded
+    };
+    if UNSET_EXPANDED.swap(false, Ordering::SeqCst) {
+        return Stage::Finished(1);
     }
-    out
-}
-
-fn main() -> rustyline::Result<()> {
-    let args_vec: Vec<String> = env::args().collect();
-    if args_vec.len() >= 3 && args_vec[1] == "-c" {
-        run_script(&args_vec[2..].join(" "))?;
-        return Ok(());
+    if state.options.xtrace {
+        let words: Vec<String> = assignments
+            .iter()
+            .map(|(name, value)| format!("{name}={value}"))
+            .chain(expanded_args.iter().cloned())
+            .collect();
+        trace_command(&words);
     }
 
-    let config: Config = ConfigBuilder::new()
-        .history_ignore_dups(true)?
-        .completion_type(CompletionType::List)
-        .edit_mode(EditMode::Emacs)
-        .build();
-
-    let mut rl: Editor<ShellHelper, FileHistory> = Editor::with_config(config)?;
-    rl.set_helper(Some(ShellHelper {
-        completer: FilenameCompleter::new(),
-        highlighter: MatchingBracketHighlighter::new(),
-        validator: MatchingBracketValidator::new(),
-        history: Vec::new(),
-    }));
+    // `command NAME ARGS...` はエイリアスも組み込みコマンドも使わず、PATH から探した外部コマンドを実行する。
+    // 先にパスに置き換えておけば、下の組み込みコマンドの振り分けには当たらない
+    if expanded_args[0] == "command" && !matches!(expanded_args.get(1).map(String::as_str), Some("-v" | "-V")) {
+        expanded_args.remove(0);
+        let Some(name) = expanded_args.first() else {
+            return Stage::Finished(0);
+        };
+        if !name.contains('/') {
+            match state.lookup_command(name) {
+                Some(path) => expanded_args[0] = path,
+                None => {
+                    eprintln!("command: {name}: 見つかりません");
+                    return Stage::Finished(127);
+                }
+            }
+        }
+    }
 
-    let hist_path = dirs::home_dir()
-        .unwrap_or_else(|| PathBuf::from("."))
-        .join(".unko_history");
-    let _ = rl.load_history(&hist_path);
+    if expanded_args[0] == "read" {
+        let status = match &cmd_info.stdin {
+            InputSource::Text(word) => builtin_read(&expanded_args, &mut expand_word(word).as_bytes(), false),
+            InputSource::File(path) => {
+                let path = expand_path(path);
+                match File::open(&path) {
+                    Ok(mut file) => builtin_read(&expanded_args, &mut file, false),
+                    Err(e) => {
+                        eprintln!("入力ファイル '{}' を開けませんでした: {}", path.display(), e);
+                        1
+                    }
+                }
+            }
+            InputSource::Inherit => match previous_stdout {
+                Some(mut stdin_pipe) => builtin_read(&expanded_args, &mut stdin_pipe, false),
+                None => {
+                    // Stdin のバッファに先読みさせないよう、fd 0 を直接読む
+                    let mut stdin = std::mem::ManuallyDrop::new(unsafe { File::from_raw_fd(0) });
+                    builtin_read(&expanded_args, &mut *stdin, unsafe { libc::isatty(0) } == 1)
+                }
+            },
+        };
+        return Stage::Finished(status);
+    }
 
-    let mut last_status = 0;
+    // `:` `true` `false` 自体は何もしないが、`${VAR:=default}` の副作用のため引数の展開は済ませておき、
+    // リダイレクション先のファイルも開いておく
+    if matches!(expanded_args[0].as_str(), ":" | "true" | "false") {
+        let opened = open_null_redirections(&cmd_info, state.options.noclobber);
+        return Stage::Finished(if opened && expanded_args[0] != "false" { 0 } else { 1 });
+    }
 
-    loop {
-        let mut full_input = String::new();
-        let mut prompt = build_prompt();
-
-        loop {
-            match rl.readline(&prompt) {
-                Ok(line) => {
-                    if full_input.is_empty() && line.trim().is_empty() {
-                        continue;
-                    }
+    if expanded_args[0] == "echo" {
+        return Stage::Finished(builtin_echo(&expanded_args, &cmd_info, stdout_pipe, state.options.noclobber));
+    }
+    if expanded_args[0] == "help" {
+        return Stage::Finished(builtin_help(&expanded_args, &cmd_info, stdout_pipe, state.options.noclobber));
+    }
+    if expanded_args[0] == "pwd" {
+        return Stage::Finished(builtin_pwd(&expanded_args, &cmd_info, stdout_pipe, state.options.noclobber));
+    }
+    if matches!(expanded_args[0].as_str(), "type" | "which" | "command") {
+        return Stage::Finished(builtin_type(&expanded_args, state, &cmd_info, stdout_pipe));
+    }
+    if expanded_args[0] == "export" {
+        return Stage::Finished(builtin_export_list(&expanded_args, state, &cmd_info, stdout_pipe));
+    }
 
-                    if line.ends_with('\\') {
-                        let mut part = line.trim_end_matches('\\').trim_end().to_string();
-                        if !full_input.trim_end().ends_with('|')
-                            && !part.trim_start().starts_with('|')
-                            && !full_input.is_empty()
-                        {
-                            full_input.push(' ');
-                        }
+    if expanded_args[0].contains('/')
+        && let Some((message, status)) = path_command_error(&expanded_args[0])
+    {
+        eprintln!("{}: {message}", expanded_args[0]);
+        return Stage::Finished(status);
+    }
+    if let Some(p) = state.lookup_command(&expanded_args[0]) {
+        expanded_args[0] = p;
+    }
 
-                        full_input.push_str(part.trim_start());
+    if !confirm_dangerous_rm(&expanded_args) {
+        eprintln!("中止しました。");
+        return Stage::Finished(1);
+    }
 
-                        prompt = "> ".into();
-                        continue;
-                    } else {
-                        let part = line.trim_end();
-                        if !full_input.trim_end().ends_with('|')
-                            && !part.trim_start().starts_with('|')
-                            && !full_input.is_empty()
-                        {
-                            full_input.push(' ');
+    let mut cmd = Command::new(&expanded_args[0]);
+    cmd.args(&expanded_args[1..]);
+    cmd.envs(assignments);
+    match group {
+        ProcessGroup::Shell => {}
+        ProcessGroup::Background(pgid) => {
+            cmd.process_group(pgid);
+        }
+        ProcessGroup::Foreground(pgid) => {
+            cmd.process_group(pgid);
+            // 新しいグループの先頭の段は、端末から読み始める前に自分で前面のグループになる。
+            // まだ背面にいるので、その間だけ SIGTTOU を無視する
+            if pgid == 0 {
+                unsafe {
+                    cmd.pre_exec(|| {
+                        if libc::isatty(0) == 1 {
+                            libc::signal(libc::SIGTTOU, libc::SIG_IGN);
+                            libc::tcsetpgrp(0, libc::getpgrp());
+                            libc::signal(libc::SIGTTOU, libc::SIG_DFL);
                         }
-                        full_input.push_str(part.trim_start());
-                        break;
-                    }
-                }
-
-                Err(ReadlineError::Interrupted) => {
-                    println!("^C");
-                    last_status = 130;
-                    full_input.clear();
-                    break;
-                }
-                Err(ReadlineError::Eof) => {
-                    println!();
-                    return Ok(());
-                }
-                Err(err) => {
-                    eprintln!("これもうわかんねぇな…: {err}");
-                    return Ok(());
+                        Ok(())
+                    });
                 }
             }
         }
+    }
 
-        let trimmed = full_input.trim();
-        if trimmed.is_empty() {
-            continue;
+    match &cmd_info.stdin {
+        InputSource::Inherit => {
+            cmd.stdin(previous_stdout.map_or_else(Stdio::inherit, Stdio::from));
         }
+        InputSource::File(path) => {
+            let path = expand_path(path);
+            match File::open(&path) {
+                Ok(file) => {
+                    cmd.stdin(Stdio::from(file));
+                }
+                Err(e) => {
+                    eprintln!("入力ファイル '{}' を開けませんでした: {}", path.display(), e);
+                    return Stage::Finished(1);
+                }
+            }
+        }
+        InputSource::Text(word) => match text_pipe(expand_word(word)) {
+            Ok(reader) => {
+                cmd.stdin(Stdio::from(reader));
+            }
+            Err(e) => {
+                eprintln!("エラー: パイプを作成できませんでした: {e}");
+                return Stage::Finished(1);
+            }
+        },
+    }
 
-        rl.add_history_entry(trimmed)?;
-        rl.helper_mut().unwrap().history.push(trimmed.to_owned());
+    let (stdout, stderr) = match output_fds(&cmd_info, stdout_pipe.map(OwnedFd::from), state.options.noclobber) {
+        Ok(fds) => fds,
+        Err(e) => {
+            eprintln!("{e}");
+            return Stage::Finished(1);
+        }
+    };
+    cmd.stderr(stderr.map_or_else(Stdio::inherit, Stdio::from));
+    cmd.stdout(stdout.map_or_else(Stdio::inherit, Stdio::from));
 
-        match parse_line(trimmed) {
-            Ok(tokens) if tokens.is_empty() => continue,
-            Ok(tokens) => {
-                let first_cmd = tokens.first().map(String::as_str).unwrap_or("");
-                if first_cmd == "cd" || first_cmd == "exit" || first_cmd == "quit" {
-                    if tokens.contains(&"|".to_string()) {
-                        eprintln!("エラー: '{}' はパイプラインでは使用できません。", first_cmd);
-                        last_status = 1;
-                        continue;
-                    }
-                    if tokens.iter().any(|t| t == ">" || t == ">>" || t == "<" || t == "2>") {
-                        eprintln!("エラー: '{}' はリダイレクションをサポートしていません。", first_cmd);
-                        last_status = 1;
-                        continue;
+    match cmd.spawn() {
+        Ok(child) => Stage::Running(child),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            eprintln!("{}: コマンドが見つかりません", expanded_args[0]);
+            Stage::Finished(127)
+        }
+        Err(e) => {
+            eprintln!("コマンド実行失敗: {}: {}", expanded_args[0], e);
+            Stage::Finished(spawn_error_status(&e))
+        }
+    }
+}
+
+// 組み込みの echo。`-n` で改行を付けず、`-e` でバックスラッシュのエスケープを解釈する (`-E` で解釈しない)。
+// 次の段はまだ起動していないので、パイプへは容量を超えても詰まらないよう別のスレッドで書く
+fn builtin_echo(argv: &[String], cmd_info: &CommandInfo, pipe: Option<PipeWriter>, noclobber: bool) -> i32 {
+    let mut newline = true;
+    let mut escapes = false;
+    let mut words = &argv[1..];
+    while let Some(flags) = words.first().and_then(|w| w.strip_prefix('-')) {
+        if flags.is_empty() || !flags.chars().all(|c| "neE".contains(c)) {
+            break;
+        }
+        for c in flags.chars() {
+            match c {
+                'n' => newline = false,
+                'e' => escapes = true,
+                _ => escapes = false,
+            }
+        }
+        words = &words[1..];
+    }
+    let joined = words.join(" ");
+    let mut text = if escapes {
+        let (unescaped, stop) = echo_unescape(&joined);
+        newline &= !stop;
+        unescaped
+    } else {
+        joined.into_bytes()
+    };
+    if newline {
+        text.push(b'\n');
+    }
+    write_builtin_output(argv, text, cmd_info, pipe, noclobber)
+}
+
+// パイプラインの段として動く組み込みコマンドの出力を、次の段・リダイレクション先・端末のどれかに書く。
+// 次の段へはスレッドから書くので、パイプの容量を超えてもシェルは止まらない
+fn write_builtin_output(
+    argv: &[String],
+    text: Vec<u8>,
+    cmd_info: &CommandInfo,
+    pipe: Option<PipeWriter>,
+    noclobber: bool,
+) -> i32 {
+    write_builtin_chunks(argv, vec![Ok(text)], cmd_info, pipe, noclobber)
+}
+
+// write_builtin_output と同じだが、標準出力 (Ok) と標準エラー出力 (Err) に分かれた出力を
+// 渡された順に書く。引数ごとに結果とエラーが混ざるコマンドでも順番が入れ替わらない
+fn write_builtin_chunks(
+    argv: &[String],
+    chunks: Vec<Result<Vec<u8>, Vec<u8>>>,
+    cmd_info: &CommandInfo,
+    pipe: Option<PipeWriter>,
+    noclobber: bool,
+) -> i32 {
+    use std::io::Write;
+    let to_pipe = pipe.is_some() && cmd_info.stdout == OutputTarget::Inherit;
+    let (stdout, stderr) = match output_fds(cmd_info, pipe.map(OwnedFd::from), noclobber) {
+        Ok(fds) => fds,
+        Err(e) => {
+            eprintln!("{e}");
+            return 1;
+        }
+    };
+    let write_all = move || -> io::Result<()> {
+        let mut out: Box<dyn Write> = match stdout {
+            Some(fd) => Box::new(File::from(fd)),
+            None => Box::new(io::stdout()),
+        };
+        let mut err: Box<dyn Write> = match stderr {
+            Some(fd) => Box::new(File::from(fd)),
+            None => Box::new(io::stderr()),
+        };
+        for chunk in chunks {
+            match chunk {
+                Ok(text) => out.write_all(&text).and_then(|()| out.flush())?,
+                Err(text) => err.write_all(&text)?,
+            }
+        }
+        Ok(())
+    };
+    let result = if to_pipe {
+        std::thread::spawn(move || {
+            let _ = write_all();
+        });
+        Ok(())
+    } else {
+        write_all()
+    };
+    match result {
+        Ok(()) => 0,
+        Err(e) => {
+            eprintln!("{}: 書き込みエラー: {e}", argv[0]);
+            1
+        }
+    }
+}
+
+// `echo -e` のエスケープ (`\n` `\t` `\\` `\0NNN` `\xHH` など) を解釈する。`\c` があればそこで出力をやめ、true を返す
+fn echo_unescape(text: &str) -> (Vec<u8>, bool) {
+    let mut out = Vec::with_capacity(text.len());
+    let mut chars = text.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            let mut buf = [0; 4];
+            out.extend_from_slice(c.encode_utf8(&mut buf).as_bytes());
+            continue;
+        }
+        let byte = match chars.next() {
+            Some('\\') => b'\\',
+            Some('a') => 0x07,
+            Some('b') => 0x08,
+            Some('e') | Some('E') => 0x1b,
+            Some('f') => 0x0c,
+            Some('n') => b'\n',
+            Some('r') => b'\r',
+            Some('t') => b'\t',
+            Some('v') => 0x0b,
+            Some('c') => return (out, true),
+            Some('0') => {
+                let mut value = 0u32;
+                for _ in 0..3 {
+                    match chars.next_if(|d| d.is_digit(8)) {
+                        Some(d) => value = value * 8 + d.to_digit(8).unwrap(),
+                        None => break,
                     }
-                    try_builtin_special(&tokens);
-                    last_status = 0;
-                } else {
-                    match parse_commands(&tokens) {
-                        Ok(pipeline) => {
-                            last_status = run_pipeline(pipeline);
-                        }
-                        Err(e) => {
-                            eprintln!("エラー: {}", e);
-                            last_status = 1;
-                        }
+                }
+                value as u8
+            }
+            Some('x') if chars.peek().is_some_and(char::is_ascii_hexdigit) => {
+                let mut value = 0u32;
+                for _ in 0..2 {
+                    match chars.next_if(char::is_ascii_hexdigit) {
+                        Some(d) => value = value * 16 + d.to_digit(16).unwrap(),
+                        None => break,
                     }
                 }
+                value as u8
+            }
+            // 知らないエスケープはそのまま残す
+            Some(other) => {
+                out.push(b'\\');
+                let mut buf = [0; 4];
+                out.extend_from_slice(other.encode_utf8(&mut buf).as_bytes());
+                continue;
+            }
+            None => b'\\',
+        };
+        out.push(byte);
+    }
+    (out, false)
+}
+
+// text を書き込むパイプの読み出し側を返す。パイプの容量を超えても詰まらないよう、
+// 書き込みは別のスレッドで行う (読み手が先に終われば EPIPE で諦める)
+fn text_pipe(text: String) -> io::Result<PipeReader> {
+    let (reader, mut writer) = io::pipe()?;
+    std::thread::spawn(move || {
+        use std::io::Write;
+        let _ = writer.write_all(text.as_bytes());
+    });
+    Ok(reader)
+}
+
+// 段の標準出力と標準エラー出力の fd を決める。None ならシェルのものをそのまま使う。
+// pipe は次の段へのパイプで、リダイレクトされる前の標準出力になる
+fn output_fds(
+    cmd_info: &CommandInfo,
+    pipe: Option<OwnedFd>,
+    noclobber: bool,
+) -> Result<(Option<OwnedFd>, Option<OwnedFd>), String> {
+    let open = |path: &Path, mode: WriteMode| {
+        let path = expand_path(path);
+        mode.open(&path, noclobber)
+            .map(OwnedFd::from)
+            .map_err(|e| format!("出力ファイル '{}' を開けませんでした: {}", path.display(), e))
+    };
+    let dup = |fd: std::os::fd::BorrowedFd<'_>| {
+        fd.try_clone_to_owned()
+            .map_err(|e| format!("エラー: ファイル記述子を複製できませんでした: {e}"))
+    };
+
+    let stdout_file = match &cmd_info.stdout {
+        OutputTarget::File(_, _) if pipe.is_some() => {
+            return Err("エラー: 出力リダイレクションはパイプラインの最後のコマンドでのみ許可されています。".to_string());
+        }
+        OutputTarget::File(path, mode) => Some(open(path, *mode)?),
+        _ => None,
+    };
+    let stderr = match (&cmd_info.stderr, &stdout_file) {
+        (OutputTarget::Inherit, _) => None,
+        // `>file 2>&1` は開き直さずに複製して、書き込み位置を標準出力と共有する
+        (target @ OutputTarget::File(..), Some(file)) if *target == cmd_info.stdout => Some(dup(file.as_fd())?),
+        (OutputTarget::File(path, mode), _) => Some(open(path, *mode)?),
+        (OutputTarget::Other, _) => Some(match &pipe {
+            Some(fd) => dup(fd.as_fd())?,
+            None => dup(io::stdout().as_fd())?,
+        }),
+    };
+    let stdout = match cmd_info.stdout {
+        OutputTarget::Inherit => pipe,
+        OutputTarget::File(..) => stdout_file,
+        OutputTarget::Other => Some(dup(io::stderr().as_fd())?),
+    };
+    Ok((stdout, stderr))
+}
+
+// UNKO_CONFIRM_RM が設定されているときだけ、`rm -rf /` のような広範囲の削除の前に確認する。
+// 端末から y/yes が返ってこなければ実行しない
+fn confirm_dangerous_rm(argv: &[String]) -> bool {
+    if env::var_os("UNKO_CONFIRM_RM").is_none_or(|v| v.is_empty()) || !is_dangerous_rm(argv) {
+        return true;
+    }
+    eprint!("{} を実行しようとしています。本当に? [y/N] ", argv.join(" "));
+    let mut answer = String::new();
+    match File::open("/dev/tty") {
+        Ok(tty) => {
+            let _ = io::BufReader::new(tty).read_line(&mut answer);
+        }
+        Err(_) => eprintln!(),
+    }
+    matches!(answer.trim(), "y" | "Y" | "yes")
+}
+
+fn is_dangerous_rm(argv: &[String]) -> bool {
+    if argv.first().and_then(|a| Path::new(a).file_name()) != Some("rm".as_ref()) {
+        return false;
+    }
+    let (mut recursive, mut force, mut options_done) = (false, false, false);
+    let mut targets = Vec::new();
+    for arg in &argv[1..] {
+        if options_done || arg == "-" || !arg.starts_with('-') {
+            targets.push(arg);
+        } else if arg == "--" {
+            options_done = true;
+        } else if let Some(long) = arg.strip_prefix("--") {
+            recursive |= long == "recursive";
+            force |= long == "force";
+        } else {
+            recursive |= arg.contains(['r', 'R']);
+            force |= arg.contains('f');
+        }
+    }
+    recursive && force && targets.iter().any(|t| is_broad_target(t))
+}
+
+// ルートやその直下、ホームやカレントディレクトリ (とその親) を広範囲とみなす
+fn is_broad_target(target: &str) -> bool {
+    let Ok(path) = fs::canonicalize(target) else {
+        return false;
+    };
+    if path.parent().is_none_or(|p| p.parent().is_none()) {
+        return true;
+    }
+    [dirs::home_dir(), env::current_dir().ok()]
+        .into_iter()
+        .flatten()
+        .any(|dir| dir.starts_with(&path))
+}
+
+// リダイレクト先のファイル名も単語として展開する
+fn expand_path(path: &Path) -> PathBuf {
+    PathBuf::from(expand_word(&path.to_string_lossy()))
+}
+
+// 起動に失敗したときのステータス。bash と同じく、見つからなければ 127、実行できなければ 126
+fn spawn_error_status(e: &io::Error) -> i32 {
+    match e.kind() {
+        io::ErrorKind::NotFound => 127,
+        io::ErrorKind::PermissionDenied => 126,
+        _ => 1,
+    }
+}
+
+// パスで指定したコマンドを起動する前に確かめる。bash と同じく、無ければ 127、
+// ディレクトリや実行権限の無いファイルなら 126 を返す
+fn path_command_error(path: &str) -> Option<(&'static str, i32)> {
+    match fs::metadata(path) {
+        Err(_) => Some(("そのようなファイルやディレクトリはありません", 127)),
+        Ok(meta) if meta.is_dir() => Some(("ディレクトリです", 126)),
+        Ok(_) if !is_executable(Path::new(path)) => Some(("実行する権限がありません", 126)),
+        Ok(_) => None,
+    }
+}
+
+fn resolve_command_path(cmd: &str) -> Option<String> {
+    if cmd.contains('/') {
+        return None;
+    }
+    for dir in path_dirs() {
+        let candidate = dir.join(cmd);
+        if candidate.is_file() {
+            return Some(candidate.to_string_lossy().into_owned());
+        }
+    }
+    None
+}
+
+// 組み込みコマンドの実行のされ方
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum BuiltinKind {
+    Special,  // パイプラインに入れずにシェル自身で実行する (run_command が try_builtin_special に渡す)
+    Pipeline, // パイプラインの段としてシェルの中で実行する (run_stage が扱う)
+}
+
+struct Builtin {
+    name: &'static str,
+    kind: BuiltinKind,
+    usage: &'static str,
+    summary: &'static str, // 空ならデバッグ用で、`help` と補完には出さない
+}
+
+const fn builtin(name: &'static str, kind: BuiltinKind, usage: &'static str, summary: &'static str) -> Builtin {
+    Builtin { name, kind, usage, summary }
+}
+
+// 組み込みコマンドの一覧。実行時の振り分け・`type`・`help`・補完はここを見る
+const BUILTINS: &[Builtin] = {
+    use BuiltinKind::{Pipeline, Special};
+    &[
+        builtin("cd", Special, "cd [DIR | -]", "カレントディレクトリを移る (`-` なら一つ前のディレクトリ)"),
+        builtin("pushd", Special, "pushd [DIR]", "今のディレクトリをスタックに積んで DIR に移る"),
+        builtin("popd", Special, "popd", "スタックから取り出したディレクトリに戻る"),
+        builtin("dirs", Special, "dirs [-c]", "ディレクトリスタックを表示する (`-c` で空にする)"),
+        builtin("pwd", Pipeline, "pwd [-L | -P]", "カレントディレクトリを表示する (`-P` ならシンボリックリンクを解決する)"),
+        builtin("echo", Pipeline, "echo [-neE] [WORD...]", "引数を空白で区切って表示する"),
+        builtin("exit", Special, "exit [N]", "シェルを終了する (N を省くと直前の終了ステータス)"),
+        builtin("quit", Special, "quit [N]", "exit と同じ"),
+        builtin(":", Pipeline, ": [ARG...]", "何もせず成功する"),
+        builtin("true", Pipeline, "true", "何もせず成功する"),
+        builtin("false", Pipeline, "false", "何もせず失敗する"),
+        builtin("read", Pipeline, "read [-r] [-p PROMPT] [NAME...]", "標準入力から一行読み、IFS で区切って変数に入れる"),
+        builtin("set", Special, "set [-o | +o] [OPTION] [-- ARG...]", "シェルオプションを表示・切り替え、位置パラメータを設定する"),
+        builtin("hash", Special, "hash [-r] [NAME...]", "コマンドのパスのハッシュ表を表示・登録・クリアする"),
+        builtin("rehash", Special, "rehash", "PATH のコマンド一覧を読み直す"),
+        builtin("export", Special, "export [-p] [NAME=VALUE...]", "子プロセスにも渡る変数を設定・一覧表示する"),
+        builtin("unset", Special, "unset NAME...", "変数を消す"),
+        builtin("expand", Special, "expand WORD...", "展開後の引数を 1 行に 1 つずつ表示する"),
+        builtin("history", Special, "history [-w]", "履歴を表示する (`-w` で履歴ファイルに書き出す)"),
+        builtin("waitfile", Special, "waitfile PATH [TIMEOUT]", "パスが現れるまで待つ"),
+        builtin("timeout", Special, "timeout SEC CMD...", "コマンドを時間制限付きで実行する"),
+        builtin("jobs", Special, "jobs [-l | -p]", "ジョブの一覧を表示する"),
+        builtin("fg", Special, "fg [JOB]", "ジョブをフォアグラウンドで再開する"),
+        builtin("bg", Special, "bg [JOB]", "止まったジョブをバックグラウンドで再開する"),
+        builtin("kill", Special, "kill [-SIGNAL] JOB | PID...", "ジョブやプロセスにシグナルを送る"),
+        builtin("wait", Special, "wait [JOB | PID...]", "バックグラウンドのジョブが終わるのを待つ"),
+        builtin("disown", Special, "disown [JOB]", "ジョブをジョブ表から外す"),
+        builtin("alias", Special, "alias [NAME=VALUE...]", "エイリアスを定義・一覧表示する"),
+        builtin("unalias", Special, "unalias [-a] NAME...", "エイリアスを消す"),
+        builtin("source", Special, "source FILE", "ファイルを今のシェルの中で実行する"),
+        builtin(".", Special, ". FILE", "source と同じ"),
+        builtin("type", Pipeline, "type NAME...", "名前がエイリアス・キーワード・組み込みコマンド・外部コマンドのどれかを表示する"),
+        builtin("which", Pipeline, "which NAME...", "外部コマンドのパスを表示する"),
+        builtin("command", Pipeline, "command [-v | -V] NAME [ARG...]", "エイリアスや組み込みコマンドを使わずに外部コマンドを実行する (`-v` `-V` なら何が実行されるかを表示する)"),
+        builtin("break", Special, "break [N]", "N 段外側までのループを抜ける"),
+        builtin("continue", Special, "continue [N]", "N 段外側のループの次の周回に進む"),
+        builtin("help", Pipeline, "help [NAME...]", "組み込みコマンドの説明を表示する"),
+        builtin("promptdbg", Special, "promptdbg", ""),
+        builtin("tokens", Special, "tokens WORD...", ""),
+    ]
+};
+
+fn builtin_kind(name: &str) -> Option<BuiltinKind> {
+    BUILTINS.iter().find(|b| b.name == name).map(|b| b.kind)
+}
+
+// `help` は組み込みコマンドの一覧を、`help NAME...` はその説明だけを表示する
+fn builtin_help(argv: &[String], cmd_info: &CommandInfo, pipe: Option<PipeWriter>, noclobber: bool) -> i32 {
+    let visible = || BUILTINS.iter().filter(|b| !b.summary.is_empty());
+    let mut text = String::new();
+    let mut status = 0;
+    if argv.len() < 2 {
+        let width = visible().map(|b| b.usage.chars().count()).max().unwrap_or(0);
+        for b in visible() {
+            let pad = width - b.usage.chars().count();
+            text.push_str(&format!("{}{}  {}\n", b.usage, " ".repeat(pad), b.summary));
+        }
+    }
+    for name in &argv[1..] {
+        match visible().find(|b| b.name == name) {
+            Some(b) => text.push_str(&format!("{}\n    {}\n", b.usage, b.summary)),
+            None => {
+                eprintln!("help: {name}: 組み込みコマンドではありません");
+                status = 1;
             }
-            Err(e) => eprintln!("{e}"),
         }
     }
+    match write_builtin_output(argv, text.into_bytes(), cmd_info, pipe, noclobber) {
+        0 => status,
+        err => err,
+    }
 }
 
-fn run_script(script: &str) -> rustyline::Result<()> {
-    for part in script.split(';') {
-        let trimmed = part.trim();
-        if trimmed.is_empty() {
+// `export` / `export -p` は環境変数を `export NAME='value'` の形で、続けてエイリアスを `alias NAME='value'` の形で
+// 出力する。パイプラインの段として動くので、`export -p > ~/.unkorc` でそのまま rc ファイルに保存できる
+fn builtin_export_list(argv: &[String], state: &ShellState, cmd_info: &CommandInfo, pipe: Option<PipeWriter>) -> i32 {
+    let mut vars: Vec<(String, String)> = env::vars().collect();
+    vars.sort();
+    let mut text = String::new();
+    for (name, value) in vars {
+        text.push_str(&format!("export {}={}\n", name, shell_escape::unix::escape(value.into())));
+    }
+    let mut aliases: Vec<_> = state.aliases.iter().collect();
+    aliases.sort();
+    for (name, value) in aliases {
+        text.push_str(&format!("{}\n", alias_line(name, value)));
+    }
+    write_builtin_output(argv, text.into_bytes(), cmd_info, pipe, state.options.noclobber)
+}
+
+// `export` と `export -p` は一覧を出すだけで変数を変えないので、特殊な組み込みコマンドとしてではなく
+// パイプラインの段として実行する
+fn lists_exports(tokens: &[String]) -> bool {
+    tokens[0] == "export"
+        && tokens[1..]
+            .iter()
+            .take_while(|t| *t != "|" && !REDIRECT_OPS.contains(&t.as_str()))
+            .all(|t| t == "-p")
+}
+
+// `export NAME=value` は変数を設定する。変数はすべて環境変数なので、
+// `export NAME` は名前を確かめるだけで何も変えない
+fn builtin_export(argv: &[String]) -> i32 {
+    let mut status = 0;
+    for arg in &argv[1..] {
+        if let Some((name, value)) = split_assignment(arg) {
+            let value = expand_word(value);
+            unsafe { env::set_var(name, value) };
             continue;
         }
-        match parse_line(trimmed) {
-            Ok(tokens) if tokens.is_empty() => {}
-            Ok(tokens) => {
-                let first = tokens.first().map(String::as_str).unwrap_or("");
-                if ["cd", "exit", "quit"].contains(&first) {
-                    try_builtin_special(&tokens);
-                } else {
-                    match parse_commands(&tokens) {
-                        Ok(pipeline) => {
-                            run_pipeline(pipeline);
+        let word = expand_word(arg);
+        let name = word.split_once('=').map_or(word.as_str(), |(name, _)| name);
+        if !is_valid_name(name) {
+            eprintln!("export: `{word}': 有効な識別子ではありません");
+            status = 1;
+        }
+    }
+    status
+}
+
+// `unset NAME...` で変数を消す。空の値を入れるのと違い、変数そのものが無くなるので子プロセスにも渡らない
+fn builtin_unset(argv: &[String]) -> i32 {
+    let mut status = 0;
+    for name in argv[1..].iter().map(|a| expand_word(a)) {
+        if name == "-v" {
+            continue;
+        }
+        if !is_valid_name(&name) {
+            eprintln!("unset: `{name}': 有効な識別子ではありません");
+            status = 1;
+            continue;
+        }
+        unsafe { env::remove_var(&name) };
+    }
+    status
+}
+
+// `type NAME...` で名前がエイリアス・キーワード・組み込みコマンド・外部コマンドのどれになるかを表示する。
+// `which` は外部コマンドならパスだけを表示する。`command -V` は type と同じで、`command -v` は
+// エイリアスなら定義を、キーワードと組み込みコマンドなら名前を、外部コマンドならパスを黙って表示する
+fn builtin_type(argv: &[String], state: &ShellState, cmd_info: &CommandInfo, pipe: Option<PipeWriter>) -> i32 {
+    let which = argv[0] == "which";
+    let command = argv[0] == "command";
+    let short = command && argv[1] == "-v";
+    let mut status = 0;
+    let mut chunks = Vec::new();
+    for name in &argv[if command { 2 } else { 1 }..] {
+        let mut text = String::new();
+        if let Some(value) = state.aliases.get(name) {
+            if short {
+                text.push_str(&format!("alias {name}={}\n", shell_escape::unix::escape(value.into())));
+            } else {
+                text.push_str(&format!("{name} is aliased to `{value}'\n"));
+            }
+        } else if KEYWORDS.contains(&name.as_str()) {
+            text.push_str(&if short { format!("{name}\n") } else { format!("{name} is a shell keyword\n") });
+        } else if builtin_kind(name).is_some() {
+            text.push_str(&if short { format!("{name}\n") } else { format!("{name} is a shell builtin\n") });
+        } else if let Some(path) = resolve_command_path(name)
+            .or_else(|| (name.contains('/') && is_executable(Path::new(name))).then(|| name.clone()))
+        {
+            if which || short {
+                text.push_str(&format!("{path}\n"));
+            } else {
+                text.push_str(&format!("{name} is {path}\n"));
+            }
+        } else {
+            if !short {
+                chunks.push(Err(format!("{}: {name}: 見つかりません\n", argv[0]).into_bytes()));
+            }
+            status = 1;
+        }
+        if !text.is_empty() {
+            chunks.push(Ok(text.into_bytes()));
+        }
+    }
+    match write_builtin_chunks(argv, chunks, cmd_info, pipe, state.options.noclobber) {
+        0 => status,
+        err => err,
+    }
+}
+
+// `pwd` (`pwd -L`) は PWD がカレントディレクトリを指していればそのまま、`pwd -P` はシンボリックリンクを
+// 解決したパスを表示する
+fn builtin_pwd(argv: &[String], cmd_info: &CommandInfo, pipe: Option<PipeWriter>, noclobber: bool) -> i32 {
+    let physical = match argv.get(1).map(String::as_str) {
+        None | Some("-L") => false,
+        Some("-P") => true,
+        Some(arg) => {
+            eprintln!("pwd: {arg}: 不正なオプションです");
+            return 2;
+        }
+    };
+    let cwd = match env::current_dir() {
+        Ok(cwd) => cwd,
+        Err(e) => {
+            eprintln!("pwd: {e}");
+            return 1;
+        }
+    };
+    let same_dir = |dir: &Path| match (fs::metadata(dir), fs::metadata(&cwd)) {
+        (Ok(a), Ok(b)) => a.dev() == b.dev() && a.ino() == b.ino(),
+        _ => false,
+    };
+    let logical = env::var_os("PWD").map(PathBuf::from).filter(|pwd| pwd.is_absolute() && same_dir(pwd));
+    let dir = match logical {
+        Some(pwd) if !physical => pwd,
+        _ => cwd.canonicalize().unwrap_or(cwd),
+    };
+    let mut text = dir.into_os_string().into_vec();
+    text.push(b'\n');
+    write_builtin_output(argv, text, cmd_info, pipe, noclobber)
+}
+
+// cd / pushd / popd はすべてここを通してディレクトリを移り、OLDPWD と PWD を更新する
+fn change_dir(path: &Path) -> io::Result<()> {
+    let old = env::current_dir().ok();
+    env::set_current_dir(path)?;
+    unsafe {
+        if let Some(old) = old {
+            env::set_var("OLDPWD", old);
+        }
+        if let Ok(cwd) = env::current_dir() {
+            env::set_var("PWD", cwd);
+        }
+    }
+    Ok(())
+}
+
+// `pushd DIR` は今のディレクトリを積んで DIR に移る。引数が無ければ今のディレクトリとスタックの先頭を入れ替える
+fn builtin_pushd(argv: &[String], state: &mut ShellState) -> i32 {
+    let cwd = env::current_dir().unwrap_or_default();
+    let target = match argv.get(1).map(|a| expand_word(a)) {
+        Some(dir) => PathBuf::from(dir),
+        None => match state.dir_stack.pop() {
+            Some(top) => top,
+            None => {
+                eprintln!("pushd: ディレクトリスタックが空です");
+                return 1;
+            }
+        },
+    };
+    if let Err(e) = change_dir(&target) {
+        eprintln!("pushd: {}: {e}", target.display());
+        if argv.len() == 1 {
+            state.dir_stack.push(target);
+        }
+        return 1;
+    }
+    state.dir_stack.push(cwd);
+    print_dir_stack(state);
+    0
+}
+
+// `popd` はスタックの先頭を取り出してそこへ戻る
+fn builtin_popd(_argv: &[String], state: &mut ShellState) -> i32 {
+    let Some(top) = state.dir_stack.pop() else {
+        eprintln!("popd: ディレクトリスタックが空です");
+        return 1;
+    };
+    if let Err(e) = change_dir(&top) {
+        eprintln!("popd: {}: {e}", top.display());
+        state.dir_stack.push(top);
+        return 1;
+    }
+    print_dir_stack(state);
+    0
+}
+
+// `dirs` はカレントディレクトリから順にスタックを 1 行に表示する。`dirs -c` でスタックを空にする
+fn builtin_dirs(argv: &[String], state: &mut ShellState) -> i32 {
+    match argv.get(1).map(|a| expand_word(a)).as_deref() {
+        None => print_dir_stack(state),
+        Some("-c") => state.dir_stack.clear(),
+        Some(arg) => {
+            eprintln!("dirs: {arg}: 不正なオプションです");
+            return 2;
+        }
+    }
+    0
+}
+
+fn print_dir_stack(state: &ShellState) {
+    let cwd = env::current_dir().unwrap_or_default();
+    let dirs: Vec<String> = std::iter::once(&cwd)
+        .chain(state.dir_stack.iter().rev())
+        .map(|dir| tilde_path(dir))
+        .collect();
+    println!("{}", dirs.join(" "));
+}
+
+fn alias_line(name: &str, value: &str) -> String {
+    format!("alias {}={}", name, shell_escape::unix::escape(value.into()))
+}
+
+fn print_alias(name: &str, value: &str) {
+    println!("{}", alias_line(name, value));
+}
+
+// `alias NAME=VALUE` で定義、`alias NAME` でその定義を、`alias` だけなら全ての定義を名前順に表示する
+fn builtin_alias(argv: &[String], state: &mut ShellState) -> i32 {
+    let args: Vec<String> = argv[1..].iter().map(|a| expand_word(a)).collect();
+    if args.is_empty() || args == ["-p"] {
+        let mut aliases: Vec<_> = state.aliases.iter().collect();
+        aliases.sort();
+        for (name, value) in aliases {
+            print_alias(name, value);
+        }
+        return 0;
+    }
+    let mut status = 0;
+    for arg in &args {
+        match arg.split_once('=') {
+            Some((name, _)) if name.is_empty() || name.contains(|c: char| "/$`'\"\\ \t|&;()<>".contains(c)) => {
+                eprintln!("alias: `{name}': 無効なエイリアス名です");
+                status = 1;
+            }
+            Some((name, value)) => {
+                state.aliases.insert(name.to_string(), value.to_string());
+            }
+            None => match state.aliases.get(arg) {
+                Some(value) => print_alias(arg, value),
+                None => {
+                    eprintln!("alias: {arg}: 見つかりません");
+                    status = 1;
+                }
+            },
+        }
+    }
+    status
+}
+
+// `unalias NAME...` で定義を消す。`unalias -a` は全て消す
+fn builtin_unalias(argv: &[String], state: &mut ShellState) -> i32 {
+    let args: Vec<String> = argv[1..].iter().map(|a| expand_word(a)).collect();
+    if args.is_empty() {
+        eprintln!("unalias: 使い方: unalias [-a] NAME...");
+        return 2;
+    }
+    if args == ["-a"] {
+        state.aliases.clear();
+        return 0;
+    }
+    let mut status = 0;
+    for name in &args {
+        if state.aliases.remove(name).is_none() {
+            eprintln!("unalias: {name}: 見つかりません");
+            status = 1;
+        }
+    }
+    status
+}
+
+// `source FILE` / `. FILE` でファイルを今のシェルの中で実行する。変数やエイリアスの変更はそのまま残る
+fn builtin_source(argv: &[String], state: &mut ShellState) -> i32 {
+    let Some(path) = argv.get(1).map(|a| expand_word(a)) else {
+        eprintln!("{}: 使い方: {} FILE", argv[0], argv[0]);
+        return 2;
+    };
+    let script = match fs::read_to_string(&path) {
+        Ok(script) => script,
+        Err(e) => {
+            eprintln!("{}: {path}: {e}", argv[0]);
+            return 1;
+        }
+    };
+    LAST_STATUS.store(0, Ordering::SeqCst);
+    // 読み込んだファイルの `break` / `continue` は呼び出し元のループには届かない
+    let loop_depth = std::mem::take(&mut state.loop_depth);
+    let _ = run_script(&script, state);
+    state.loop_depth = loop_depth;
+    LAST_STATUS.load(Ordering::SeqCst)
+}
+
+fn builtin_hash(argv: &[String], state: &mut ShellState) -> i32 {
+    let args: Vec<String> = argv[1..].iter().map(|a| expand_word(a)).collect();
+    if args.is_empty() {
+        if state.hashed.is_empty() {
+            println!("hash: ハッシュ表は空です");
+            return 0;
+        }
+        let mut entries: Vec<_> = state.hashed.iter().collect();
+        entries.sort_by(|a, b| a.0.cmp(b.0));
+        println!("hits\tcommand");
+        for (_, entry) in entries {
+            println!("{:>4}\t{}", entry.hits, entry.path);
+        }
+        return 0;
+    }
+
+    let mut status = 0;
+    for arg in &args {
+        if arg == "-r" {
+            state.hashed.clear();
+        } else if state.lookup_command(arg).is_some() {
+            // `hash NAME` は登録するだけで、実行回数には数えない
+            if let Some(entry) = state.hashed.get_mut(arg.as_str()) {
+                entry.hits = 0;
+            }
+        } else {
+            eprintln!("hash: {arg}: 見つかりません");
+            status = 1;
+        }
+    }
+    status
+}
+
+fn builtin_set(argv: &[String], state: &mut ShellState) -> i32 {
+    // `set -- ARG...` は `--` の後ろの引数を `$1` 以降にする (`$0` はそのまま)。
+    // `"$@"` が一つずつ別の引数になるよう、コマンドの引数と同じように展開する
+    let end = argv.iter().position(|a| a == "--").unwrap_or(argv.len());
+    let args: Vec<String> = argv[1..end].iter().map(|a| expand_word(a)).collect();
+    if argv.len() == 1 {
+        let mut vars: Vec<(String, String)> = env::vars().collect();
+        vars.sort();
+        for (name, value) in vars {
+            println!("{}={}", name, shell_escape::unix::escape(value.into()));
+        }
+        return 0;
+    }
+
+    let mut status = 0;
+    let mut it = args.iter();
+    while let Some(flag) = it.next() {
+        let on = match flag.as_str() {
+            "-o" => true,
+            "+o" => false,
+            _ => {
+                // `-f` や `+f` のような一文字オプション
+                let (on, letters) = match (flag.strip_prefix('-'), flag.strip_prefix('+')) {
+                    (Some(rest), _) if !rest.is_empty() => (true, rest),
+                    (_, Some(rest)) if !rest.is_empty() => (false, rest),
+                    _ => {
+                        eprintln!("set: {flag}: 無効なオプションです");
+                        return 2;
+                    }
+                };
+                for letter in letters.chars() {
+                    match ShellOptions::short_name(letter) {
+                        Some(name) => {
+                            state.options.set(name, on);
                         }
-                        Err(e) => {
-                            eprintln!("エラー: {}", e);
+                        None => {
+                            eprintln!("set: -{letter}: 無効なオプションです");
+                            return 2;
                         }
                     }
                 }
+                continue;
+            }
+        };
+        match it.next() {
+            Some(name) => {
+                if !state.options.set(name, on) {
+                    eprintln!("set: {name}: 無効なオプション名です");
+                    status = 1;
+                }
+            }
+            None if on => {
+                for name in ShellOptions::NAMES {
+                    let value = state.options.get(name).unwrap_or(false);
+                    println!("{:<15}\t{}", name, if value { "on" } else { "off" });
+                }
+            }
+            None => {
+                for name in ShellOptions::NAMES {
+                    let value = state.options.get(name).unwrap_or(false);
+                    println!("set {} {}", if value { "-o" } else { "+o" }, name);
+                }
             }
-            Err(e) => eprintln!("{e}"),
         }
     }
-    Ok(())
-}
\ No newline at end of file
+    if end < argv.len() {
+        let params: Vec<String> = argv[end + 1..].iter().flat_map(|a| expand_arg(a, &state.options)).collect();
+        let mut positional = POSITIONAL.write().unwrap();
+        positional.truncate(1);
+        positional.extend(params);
+    }
+    status
+}
+
+// 組み込みコマンドなら実行して終了ステータスを返す。組み込みでなければ None
+fn try_builtin_special(argv: &[String], state: &mut ShellState) -> Option<i32> {
+    match argv.first().map(String::as_str) {
+        Some("cd") => {
+            let result = match argv.get(1).map(|a| expand_word(a)).as_deref() {
+                // `cd -` は一つ前のディレクトリに戻り、移った先を表示する
+                Some("-") => match env::var_os("OLDPWD").filter(|dir| !dir.is_empty()) {
+                    Some(dir) => change_dir(Path::new(&dir)).map(|()| println!("{}", dir.to_string_lossy())),
+                    None => {
+                        eprintln!("cd: OLDPWD が設定されていません");
+                        return Some(1);
+                    }
+                },
+                Some(path) => change_dir(Path::new(path)),
+                None => {
+                    let home = dirs::home_dir().unwrap_or_else(|| {
+                        eprintln!("cd: (ホームディレクトリが分から)ないです");
+                        env::current_dir().unwrap_or_else(|_| PathBuf::from("."))
+                    });
+                    change_dir(&home)
+                }
+            };
+            match result {
+                Ok(()) => Some(0),
+                Err(e) => {
+                    eprintln!("cd: {e}");
+                    Some(1)
+                }
+            }
+        }
+        Some("pushd") => Some(builtin_pushd(argv, state)),
+        Some("popd") => Some(builtin_popd(argv, state)),
+        Some("dirs") => Some(builtin_dirs(argv, state)),
+        // 引数が無ければ直前のコマンドの終了ステータスで終わる
+        Some("exit") | Some("quit") => {
+            let code = match argv.get(1).map(|a| expand_word(a)) {
+                Some(arg) => arg.parse::<i32>().unwrap_or_else(|_| {
+                    eprintln!("{}: {arg}: 数値の引数が必要です", argv[0]);
+                    2
+                }),
+                None => LAST_STATUS.load(Ordering::SeqCst),
+            };
+            run_logout_file(state);
+            state.save_history();
+            exit_shell(code);
+        }
+        Some("set") => {
+            let status = builtin_set(argv, state);
+            *OPTIONS.write().unwrap() = state.options.clone();
+            Some(status)
+        }
+        Some("hash") => Some(builtin_hash(argv, state)),
+        // 補完用のコマンド一覧を PATH から読み直し、ハッシュ表も捨てる
+        Some("rehash") => {
+            *BIN_CACHE.write().unwrap() = scan_path_binaries();
+            state.hashed.clear();
+            Some(0)
+        }
+        Some("export") => Some(builtin_export(argv)),
+        Some("unset") => Some(builtin_unset(argv)),
+        Some("history") => Some(builtin_history(argv, state)),
+        Some("waitfile") => Some(builtin_waitfile(argv)),
+        Some("timeout") => Some(builtin_timeout(argv, state)),
+        Some("jobs") => Some(builtin_jobs(argv, state)),
+        Some("fg") => Some(builtin_fg(argv, state)),
+        Some("bg") => Some(builtin_bg(argv, state)),
+        Some("kill") => Some(builtin_kill(argv, state)),
+        Some("wait") => Some(builtin_wait(argv, state)),
+        Some("disown") => Some(builtin_disown(argv, state)),
+        Some("alias") => Some(builtin_alias(argv, state)),
+        Some("unalias") => Some(builtin_unalias(argv, state)),
+        Some("source") | Some(".") => Some(builtin_source(argv, state)),
+        // 実行せずに、コマンドに渡るはずの引数を 1 行に 1 つずつ表示する
+        Some("expand") => {
+            for word in argv[1..].iter().flat_map(|a| expand_arg(a, &state.options)) {
+                println!("{}", shell_escape::unix::escape(Cow::from(word)));
+            }
+            Some(0)
+        }
+        // 字句解析の確認用 (隠しコマンド)。引数を parse_line に通したトークンを 1 行に 1 つ、
+        // 演算子か単語かの区別と、クォートを残したままの形で表示する
+        Some("tokens") => {
+            let line: Vec<String> = argv[1..].iter().map(|a| expand_word(a)).collect();
+            match parse_line(&line.join(" ")) {
+                Ok(tokens) => {
+                    for token in tokens {
+                        let kind = match token.as_str() {
+                            "|" | "(" | ")" | ";" | "&" | "&&" | "||" => "op",
+                            op if REDIRECT_OPS.contains(&op) => "op",
+                            t if t.starts_with("<(") || t.starts_with(">(") => "procsub",
+                            _ => "word",
+                        };
+                        println!("{kind:<8}{token}");
+                    }
+                    Some(0)
+                }
+                Err(e) => {
+                    eprintln!("{e}");
+                    Some(2)
+                }
+            }
+        }
+        // プロンプト文字列の確認用 (隠しコマンド)。エスケープシーケンスも見える形で表示する
+        Some("promptdbg") => {
+            let rendered = match argv.get(1) {
+                Some(template) => render_prompt(&expand_word(template)),
+                None => build_prompt(LAST_STATUS.load(Ordering::SeqCst)),
+            };
+            let visible: String = rendered
+                .chars()
+                .map(|c| match c {
+                    '\x1b' => "\\e".to_string(),
+                    '\n' => "\\n".to_string(),
+                    c if c.is_control() => format!("\\x{:02x}", c as u32),
+                    c => c.to_string(),
+                })
+                .collect();
+            println!("{visible}");
+            Some(0)
+        }
+        _ => None,
+    }
+}
+
+type Chars<'a> = std::iter::Peekable<std::str::Chars<'a>>;
+
+// 開きクォート (バッククォートを含む) の直後から閉じクォートまでを、クォート文字も含めてそのまま out に追加する。
+// ダブルクォート内の `$(...)` / `${...}` / `` `...` `` は中に `"` を含んでもよいので括弧単位で読む。
+// pos は開きクォートの位置で、エラーの報告にだけ使う
+fn read_quoted(
+    chars: &mut Chars<'_>,
+    quote: char,
+    pos: usize,
+    out: &mut String,
+) -> Result<(), ParseError> {
+    out.push(quote);
+    while let Some(c) = chars.next() {
+        out.push(c);
+        match c {
+            _ if c == quote => return Ok(()),
+            '\\' if quote != '\'' => {
+                if let Some(n) = chars.next() {
+                    out.push(n);
+                }
+            }
+            '`' if quote == '"' => {
+                out.pop();
+                read_quoted(chars, '`', pos, out)?;
+            }
+            '$' if quote == '"' && matches!(chars.peek(), Some('(') | Some('{')) => {
+                let open = chars.next().unwrap();
+                out.push(open);
+                read_group(chars, open, pos, out)?;
+            }
+            _ => {}
+        }
+    }
+    Err(ParseError::UnclosedQuote { quote, pos })
+}
+
+// 開き括弧の直後から対応する閉じ括弧までを out に追加する (中のクォートも考慮する)
+fn read_group(
+    chars: &mut Chars<'_>,
+    open: char,
+    pos: usize,
+    out: &mut String,
+) -> Result<(), ParseError> {
+    let close = if open == '(' { ')' } else { '}' };
+    let mut depth = 1;
+    while let Some(c) = chars.next() {
+        match c {
+            '\'' | '"' | '`' => read_quoted(chars, c, pos, out)?,
+            '\\' => {
+                out.push(c);
+                if let Some(n) = chars.next() {
+                    out.push(n);
+                }
+            }
+            _ => {
+                out.push(c);
+                if c == open {
+                    depth += 1;
+                } else if c == close {
+                    depth -= 1;
+                    if depth == 0 {
+                        return Ok(());
+                    }
+                }
+            }
+        }
+    }
+    Err(ParseError::UnclosedBlock { open, pos })
+}
+
+// 入力行をトークンに分割する。
+// 単語のクォートやエスケープはここでは外さずに残し、展開時 (expand_word) に処理する。
+// こうしておくと `'|'` のようにクォートされた記号が演算子と区別でき、
+// シングルクォート内の `$` が展開されることもない
+fn parse_line(input: &str) -> Result<Vec<String>, ParseError> {
+    let mut tokens: Vec<String> = Vec::new();
+    let mut current = String::new();
+    let total = input.chars().count();
+    let mut chars = input.chars().peekable();
+    // 直前に読んだ文字の位置 (エラー報告用なので必要な時だけ数える)
+    let pos_of = |chars: &Chars<'_>| total - chars.clone().count() - 1;
+    // 本文をまだ読んでいないヒアドキュメントの演算子の位置
+    let mut heredocs: Vec<usize> = Vec::new();
+
+    while let Some(c) = chars.next() {
+        // `[[ ... ]]` の `=~` の右辺 (正規表現) は `(` や `|` も含めて一つの単語にする
+        if "|()<>".contains(c) && in_conditional(&tokens) && tokens.last().is_some_and(|t| t == "=~") {
+            current.push(c);
+            continue;
+        }
+        match c {
+            // ヒアドキュメントの本文は、演算子のある行の次の行から始まる
+            '\n' if !heredocs.is_empty() => {
+                if !current.is_empty() {
+                    tokens.push(std::mem::take(&mut current));
+                }
+                for op in std::mem::take(&mut heredocs) {
+                    read_heredoc(&mut chars, &mut tokens, op)?;
+                }
+            }
+            ' ' | '\t' | '\n' => {
+                if !current.is_empty() {
+                    tokens.push(std::mem::take(&mut current));
+                }
+            }
+            // 単語の先頭の `#` から行末まではコメント。`foo#bar` やクォートの中の `#` はそのまま
+            '#' if current.is_empty() => {
+                while chars.next_if(|&n| n != '\n').is_some() {}
+            }
+            // `<<<` はヒアストリング、`<<` と `<<-` はヒアドキュメント
+            '<' if chars.peek() == Some(&'<') => {
+                chars.next();
+                if !current.is_empty() {
+                    tokens.push(std::mem::take(&mut current));
+                }
+                if chars.next_if_eq(&'<').is_some() {
+                    tokens.push("<<<".to_string());
+                } else {
+                    let op = if chars.next_if_eq(&'-').is_some() { "<<-" } else { "<<" };
+                    heredocs.push(tokens.len());
+                    tokens.push(op.to_string());
+                }
+            }
+            '\'' | '"' | '`' => {
+                let pos = pos_of(&chars);
+                read_quoted(&mut chars, c, pos, &mut current)?
+            }
+            '\\' => match chars.next() {
+                Some('\n') => {} // 行継続
+                Some(n) => {
+                    current.push('\\');
+                    current.push(n);
+                }
+                None => current.push('\\'),
+            },
+            '$' => {
+                current.push('$');
+                if let Some(&open @ ('(' | '{')) = chars.peek() {
+                    chars.next();
+                    current.push(open);
+                    let pos = pos_of(&chars);
+                    read_group(&mut chars, open, pos, &mut current)?;
+                }
+            }
+            '>' | '<' if chars.peek() == Some(&'(') => {
+                if !current.is_empty() {
+                    tokens.push(std::mem::take(&mut current));
+                }
+                let mut token = String::from(c); // '>' もしくは '<'
+                token.push(chars.next().unwrap()); // '('
+                let pos = pos_of(&chars);
+                read_group(&mut chars, '(', pos, &mut token)?;
+                tokens.push(token);
+            }
+            '|' | '<' => {
+                if !current.is_empty() {
+                    tokens.push(std::mem::take(&mut current));
+                }
+                // `|&` は `2>&1 |` の短縮形
+                if c == '|' && chars.next_if_eq(&'&').is_some() {
+                    tokens.push("2>&1".to_string());
+                }
+                if c == '|' && chars.next_if_eq(&'|').is_some() {
+                    tokens.push("||".to_string());
+                    continue;
+                }
+                tokens.push(c.to_string());
+            }
+            // コマンドの位置の `((` は算術式のコマンドで、`))` までを一つのトークンにする。
+            // `((a) )` のように `))` で閉じていなければ入れ子のサブシェルとして読む
+            '(' if current.is_empty()
+                && chars.peek() == Some(&'(')
+                && tokens.last().is_none_or(|t| starts_command_after(t, *command_positions(&tokens).last().unwrap())) =>
+            {
+                let pos = pos_of(&chars);
+                let mut ahead = chars.clone();
+                ahead.next();
+                let mut inner = String::new();
+                read_group(&mut ahead, '(', pos, &mut inner)?;
+                if ahead.next_if_eq(&')').is_some() {
+                    chars = ahead;
+                    tokens.push(format!("(({inner})"));
+                } else {
+                    tokens.push(c.to_string());
+                }
+            }
+            // `&>file` `&>>file` は標準出力と標準エラー出力の両方をファイルへ
+            '&' if chars.peek() == Some(&'>') => {
+                chars.next();
+                if !current.is_empty() {
+                    tokens.push(std::mem::take(&mut current));
+                }
+                let op = if chars.next_if_eq(&'>').is_some() { "&>>" } else { "&>" };
+                tokens.push(op.to_string());
+            }
+            '&' if chars.peek() == Some(&'&') => {
+                chars.next();
+                if !current.is_empty() {
+                    tokens.push(std::mem::take(&mut current));
+                }
+                tokens.push("&&".to_string());
+            }
+            '(' | ')' | ';' | '&' => {
+                if !current.is_empty() {
+                    tokens.push(std::mem::take(&mut current));
+                }
+                tokens.push(c.to_string());
+            }
+            '>' => {
+                if !current.is_empty() {
+                    tokens.push(std::mem::take(&mut current));
+                }
+                if chars.next_if_eq(&'>').is_some() {
+                    tokens.push(">>".to_string());
+                } else if chars.next_if_eq(&'|').is_some() {
+                    tokens.push(">|".to_string());
+                } else if chars.next_if_eq(&'&').is_some() {
+                    // `>&2` は複製、`>&1` は何もしない。`>&file` は `&>file` と同じ
+                    match chars.next_if(|&d| d == '1' || d == '2') {
+                        Some('2') => tokens.push(">&2".to_string()),
+                        Some(_) => {}
+                        None => tokens.push("&>".to_string()),
+                    }
+                } else {
+                    tokens.push(">".to_string());
+                }
+            }
+            // `1>` `2>` `2>>` と fd の複製 `2>&1` `1>&2`。`2>&2` のように同じ fd への複製は何もしない
+            '1' | '2' if current.is_empty() && chars.peek() == Some(&'>') => {
+                chars.next(); // consume '>'
+                let prefix = if c == '2' { "2" } else { "" };
+                let mut ahead = chars.clone();
+                if let (Some('&'), Some(fd @ ('1' | '2'))) = (ahead.next(), ahead.next()) {
+                    chars.nth(1);
+                    if fd != c {
+                        tokens.push(format!("{prefix}>&{fd}"));
+                    }
+                } else if chars.next_if_eq(&'>').is_some() {
+                    tokens.push(format!("{prefix}>>"));
+                } else if chars.next_if_eq(&'|').is_some() {
+                    tokens.push(format!("{prefix}>|"));
+                } else {
+                    tokens.push(format!("{prefix}>"));
+                }
+            }
+            _ => current.push(c),
+        }
+    }
+
+    if !current.is_empty() {
+        tokens.push(std::mem::take(&mut current));
+    }
+    if let Some(&op) = heredocs.first() {
+        let delimiter = tokens.get(op + 1).map(|d| heredoc_delimiter(d).0).unwrap_or_default();
+        return Err(ParseError::UnclosedHeredoc { delimiter });
+    }
+    Ok(tokens)
+}
+
+// クォートを外した終わりの行と、クォートされていたか (されていれば本文を展開しない)
+fn heredoc_delimiter(word: &str) -> (String, bool) {
+    let delimiter = word.chars().filter(|c| !matches!(c, '\'' | '"' | '\\')).collect();
+    (delimiter, word.contains(['\'', '"', '\\']))
+}
+
+// tokens[op] のヒアドキュメントの本文を終わりの行まで読み、終わりの行の単語と置き換える。
+// 本文は後で expand_word に渡せるよう、展開しないならシングルクォートで、
+// 展開するならダブルクォートで囲んだ単語にする (ヒアドキュメントの中の `"` はただの文字)
+fn read_heredoc(chars: &mut Chars<'_>, tokens: &mut [String], op: usize) -> Result<(), ParseError> {
+    let strip_tabs = tokens[op] == "<<-";
+    let Some(word) = tokens.get(op + 1) else {
+        return Err(ParseError::MissingRedirectTarget { op: tokens[op].clone(), pos: op });
+    };
+    let (delimiter, quoted) = heredoc_delimiter(word);
+    let mut body = String::new();
+    loop {
+        if chars.peek().is_none() {
+            return Err(ParseError::UnclosedHeredoc { delimiter });
+        }
+        let mut line: String = chars.by_ref().take_while(|&c| c != '\n').collect();
+        if strip_tabs {
+            line = line.trim_start_matches('\t').to_string();
+        }
+        if line == delimiter {
+            break;
+        }
+        body.push_str(&line);
+        body.push('\n');
+    }
+
+    tokens[op + 1] = if quoted {
+        shell_escape::unix::escape(Cow::from(body)).into_owned()
+    } else {
+        let mut word = String::from('"');
+        let mut body = body.chars().peekable();
+        while let Some(c) = body.next() {
+            match c {
+                '"' => word.push_str("\\\""),
+                // `\"` は本文ではそのまま残るので、`\` と `"` を両方エスケープする
+                '\\' if body.peek() == Some(&'"') => {
+                    body.next();
+                    word.push_str("\\\\\\\"");
+                }
+                '\\' => {
+                    word.push(c);
+                    if let Some(n) = body.next() {
+                        word.push(n);
+                    }
+                }
+                _ => word.push(c),
+            }
+        }
+        word.push('"');
+        word
+    };
+    Ok(())
+}
+
+// 直前の `[[` がまだ `]]` で閉じられていなければ、条件式の中を読んでいる
+fn in_conditional(tokens: &[String]) -> bool {
+    tokens.iter().rev().find(|t| *t == "[[" || *t == "]]").is_some_and(|t| t == "[[")
+}
+
+// `$(...)` の中身を別プロセスの unko で実行し、標準出力を置換結果として返す。
+// 出力はバイト列のまま受け取り、引数として使う時点で初めて String に変換する。
+// 子には `$?` と位置パラメータ (`$0` も) と、bash と同じく errexit 以外のシェルオプションを渡す。変数は環境変数なのでそのまま見える。
+// エイリアス・ジョブ・ディレクトリスタック・コマンドのハッシュ表・履歴は引き継がない
+fn command_substitution(script: &str) -> String {
+    let exe = env::current_exe()
+        .unwrap_or_else(|_| PathBuf::from(env::args().next().unwrap_or_default()));
+    let options = OPTIONS.read().unwrap().clone();
+    let mut child = Command::new(exe);
+    for name in ShellOptions::NAMES.into_iter().filter(|&name| name != "errexit") {
+        if options.get(name) == Some(true) {
+            child.arg("-o").arg(name);
+        }
+    }
+    match child
+        .arg("-c")
+        .arg(script)
+        .args(POSITIONAL.read().unwrap().iter())
+        .env("UNKO_LAST_STATUS", LAST_STATUS.load(Ordering::SeqCst).to_string())
+        .stdin(Stdio::inherit())
+        .stderr(Stdio::inherit())
+        .output()
+    {
+        Ok(output) => substitution_text(output.stdout, options.strictutf8),
+        Err(e) => {
+            eprintln!("コマンド置換に失敗しました: {e}");
+            String::new()
+        }
+    }
+}
+
+// 開きバッククォートの直後から閉じバッククォートまでを読んでコマンド置換する。
+// 中の `\$` `` \` `` `\\` はバックスラッシュを取り除き、それ以外の `\` はそのまま残す
+fn backquote_substitution(chars: &mut Chars<'_>) -> String {
+    let mut script = String::new();
+    while let Some(c) = chars.next() {
+        match c {
+            '`' => break,
+            '\\' => match chars.next_if(|n| matches!(n, '$' | '`' | '\\')) {
+                Some(n) => script.push(n),
+                None => script.push(c),
+            },
+            _ => script.push(c),
+        }
+    }
+    command_substitution(&script)
+}
+
+// 引数や環境変数は String で受け渡すため、UTF-8 として不正なバイトは U+FFFD に置き換える
+// (元のバイト列は保たれない)。strict (`set -o strictutf8`) なら置き換えずにエラーを表示して空にする。
+// NUL は引数に含められないので bash と同様に捨てる
+fn substitution_text(mut bytes: Vec<u8>, strict: bool) -> String {
+    if bytes.contains(&0) {
+        eprintln!("警告: コマンド置換の出力に含まれる NUL バイトを無視しました");
+        bytes.retain(|&b| b != 0);
+    }
+    while bytes.last() == Some(&b'\n') {
+        bytes.pop();
+    }
+    match String::from_utf8(bytes) {
+        Ok(s) => s,
+        Err(e) if strict => {
+            eprintln!("unko: コマンド置換の出力が UTF-8 として不正です: {e}");
+            String::new()
+        }
+        Err(e) => String::from_utf8_lossy(e.as_bytes()).into_owned(),
+    }
+}
+
+// 未設定の変数を展開したときの値。nounset が有効ならエラーを表示して UNSET_EXPANDED に記録する
+fn unset_value(name: &str) -> String {
+    if OPTIONS.read().unwrap().nounset {
+        eprintln!("unko: {name}: 未設定の変数です");
+        UNSET_EXPANDED.store(true, Ordering::SeqCst);
+    }
+    String::new()
+}
+
+// 特殊パラメータ (`?`, `#`, `@`, 数字の位置パラメータ) か環境変数の値
+fn param_value(name: &str) -> Option<String> {
+    match name {
+        "?" => Some(LAST_STATUS.load(Ordering::SeqCst).to_string()),
+        "#" => Some((POSITIONAL.read().unwrap().len().saturating_sub(1)).to_string()),
+        "@" => Some(POSITIONAL.read().unwrap().get(1..).unwrap_or_default().join(" ")),
+        _ if !name.is_empty() && name.chars().all(|c| c.is_ascii_digit()) => {
+            let index = name.parse::<usize>().ok()?;
+            POSITIONAL.read().unwrap().get(index).cloned()
+        }
+        _ => env::var(name).ok(),
+    }
+}
+
+// `${...}` の中身を展開する。`${VAR:-word}` と `${VAR:=word}` に対応
+fn expand_param(expr: &str) -> String {
+    let Some(idx) = expr.find(':') else {
+        return param_value(expr).unwrap_or_else(|| unset_value(expr));
+    };
+    let (name, rest) = (&expr[..idx], &expr[idx + 1..]);
+    let value = param_value(name).filter(|v| !v.is_empty());
+    if let Some(word) = rest.strip_prefix('-') {
+        value.unwrap_or_else(|| expand_word(word))
+    } else if let Some(word) = rest.strip_prefix('=') {
+        value.unwrap_or_else(|| {
+            let word = expand_word(word);
+            unsafe { env::set_var(name, &word); }
+            word
+        })
+    } else {
+        env::var(expr).unwrap_or_default()
+    }
+}
+
+// `$` の直後から変数名・`$?`・`$#`・`$0`〜`$9`・`${...}`・`$(...)` を読み、展開結果を out に追加する
+fn expand_dollar(chars: &mut Chars<'_>, out: &mut String) {
+    if let Some(c) = chars.next_if(|&c| c == '?' || c == '#' || c.is_ascii_digit()) {
+        let name = c.to_string();
+        out.push_str(&param_value(&name).unwrap_or_else(|| unset_value(&name)));
+        return;
+    }
+    if let Some(&open @ ('(' | '{')) = chars.peek() {
+        chars.next();
+        let mut inner = String::new();
+        if read_group(chars, open, 0, &mut inner).is_ok() {
+            inner.pop(); // 閉じ括弧
+        }
+        if open == '(' && inner.starts_with('(') && inner.ends_with(')') {
+            // `$(( ... ))` は算術展開
+            match eval_arith(&expand_word(&inner[1..inner.len() - 1])) {
+                Ok(value) => out.push_str(&value.to_string()),
+                Err(e) => eprintln!("unko: {inner}: {e}"),
+            }
+        } else if open == '(' {
+            out.push_str(&command_substitution(&inner));
+        } else {
+            out.push_str(&expand_param(&inner));
+        }
+        return;
+    }
+    let mut name = String::new();
+    while let Some(&ch) = chars.peek() {
+        if ch.is_alphanumeric() || ch == '_' {
+            name.push(ch);
+            chars.next();
+        } else {
+            break;
+        }
+    }
+    if name.is_empty() {
+        out.push('$');
+    } else {
+        out.push_str(&env::var(&name).unwrap_or_else(|_| unset_value(&name)));
+    }
+}
+
+// `$` の直後が `@` か `{@}` なら読み進めて true を返す
+fn take_all_params(chars: &mut Chars<'_>) -> bool {
+    if chars.next_if_eq(&'@').is_some() {
+        return true;
+    }
+    let mut ahead = chars.clone();
+    if ahead.next() == Some('{') && ahead.next() == Some('@') && ahead.next() == Some('}') {
+        *chars = ahead;
+        return true;
+    }
+    false
+}
+
+// `$@` を展開する。位置パラメータ一つずつを別の単語にし、前後の文字は最初と最後の単語につなげる
+fn push_all_params(out: &mut ExpandedWord, quoted: bool) {
+    let params = POSITIONAL.read().unwrap();
+    let params = params.get(1..).unwrap_or_default();
+    if params.is_empty() {
+        out.no_params = true;
+    }
+    for (i, param) in params.iter().enumerate() {
+        if i > 0 {
+            out.break_field();
+        }
+        if quoted {
+            out.push_str(param, true);
+        } else {
+            out.push_split(param);
+        }
+    }
+}
+
+// `~user` 用に getpwnam_r でユーザーのホームディレクトリを引く
+fn user_home_dir(user: &str) -> Option<String> {
+    let name = CString::new(user).ok()?;
+    let mut buf_len = 1024;
+    loop {
+        let mut pwd: libc::passwd = unsafe { std::mem::zeroed() };
+        let mut buf = vec![0 as libc::c_char; buf_len];
+        let mut result: *mut libc::passwd = std::ptr::null_mut();
+        let rc = unsafe {
+            libc::getpwnam_r(name.as_ptr(), &mut pwd, buf.as_mut_ptr(), buf.len(), &mut result)
+        };
+        if rc == libc::ERANGE && buf_len < 1 << 20 {
+            buf_len *= 2;
+            continue;
+        }
+        if rc != 0 || result.is_null() || pwd.pw_dir.is_null() {
+            return None;
+        }
+        let dir = unsafe { CStr::from_ptr(pwd.pw_dir) };
+        return Some(dir.to_string_lossy().into_owned());
+    }
+}
+
+// コマンドの引数一つを展開した結果。run_pipeline と `expand` で同じ展開を使う
+fn expand_arg(word: &str, options: &ShellOptions) -> Vec<String> {
+    brace_expand(word)
+        .iter()
+        .flat_map(|word| expand_braced_arg(word, options))
+        .collect()
+}
+
+fn expand_braced_arg(word: &str, options: &ShellOptions) -> Vec<String> {
+    let word = expand_word_parts(word, true);
+    // クォートされていない展開や空の `$@` だけからできた空の単語は引数にしない
+    if (word.no_params || !word.quoted) && word.fields.is_empty() && word.text.is_empty() {
+        return Vec::new();
+    }
+    word.into_fields()
+        .into_iter()
+        .flat_map(|word| {
+            if word.has_glob && !options.noglob {
+                let matches = glob_paths(&word.pattern, options);
+                // 何にも一致しなければ bash と同じくパターンをそのまま渡す
+                if !matches.is_empty() {
+                    return matches;
+                }
+            }
+            vec![word.text]
+        })
+        .collect()
+}
+
+// ブレース展開。クォートの外の `{a,b}` (入れ子も可) と `{1..5}` / `{1..10..2}` / `{a..e}` を
+// 変数展開やグロブより先に、parse_line が残したままの単語の上で展開する。展開できない `{...}` はそのまま残す
+fn brace_expand(word: &str) -> Vec<String> {
+    let chars: Vec<char> = word.chars().collect();
+    let mut i = 0;
+    while i < chars.len() {
+        match chars[i] {
+            '\'' | '"' | '\\' | '`' => {
+                i = skip_quoted(&chars, i);
+                continue;
+            }
+            '$' if matches!(chars.get(i + 1), Some('{') | Some('(')) => {
+                i = skip_group(&chars, i + 1);
+                continue;
+            }
+            '{' => {
+                if let Some((close, items)) = brace_items(&chars, i) {
+                    let prefix: String = chars[..i].iter().collect();
+                    let suffix: String = chars[close + 1..].iter().collect();
+                    return items
+                        .iter()
+                        .map(|item| format!("{prefix}{item}{suffix}"))
+                        .filter(|word| !word.is_empty())
+                        .flat_map(|word| brace_expand(&word))
+                        .collect();
+                }
+            }
+            _ => {}
+        }
+        i += 1;
+    }
+    vec![word.to_string()]
+}
+
+// chars[open] の `{` に対応する `}` の位置と、展開した中身を返す
+fn brace_items(chars: &[char], open: usize) -> Option<(usize, Vec<String>)> {
+    let mut depth = 0;
+    let mut commas = Vec::new();
+    let mut j = open + 1;
+    while j < chars.len() {
+        match chars[j] {
+            '\'' | '"' | '\\' | '`' => {
+                j = skip_quoted(chars, j);
+                continue;
+            }
+            '$' if matches!(chars.get(j + 1), Some('{') | Some('(')) => {
+                j = skip_group(chars, j + 1);
+                continue;
+            }
+            '{' => depth += 1,
+            '}' if depth == 0 => break,
+            '}' => depth -= 1,
+            ',' if depth == 0 => commas.push(j),
+            _ => {}
+        }
+        j += 1;
+    }
+    if j >= chars.len() {
+        return None;
+    }
+    if commas.is_empty() {
+        let inner: String = chars[open + 1..j].iter().collect();
+        return brace_sequence(&inner).map(|items| (j, items));
+    }
+    let mut items = Vec::new();
+    let mut start = open + 1;
+    for end in commas.into_iter().chain([j]) {
+        items.push(chars[start..end].iter().collect());
+        start = end + 1;
+    }
+    Some((j, items))
+}
+
+// `{1..5}` `{10..1..3}` `{01..10}` `{a..e}` の中身
+fn brace_sequence(inner: &str) -> Option<Vec<String>> {
+    let parts: Vec<&str> = inner.split("..").collect();
+    let (start, end, step) = match parts[..] {
+        [start, end] => (start, end, 1),
+        [start, end, step] => (start, end, step.parse::<i64>().ok()?.checked_abs()?.max(1)),
+        _ => return None,
+    };
+    if let (Ok(a), Ok(b)) = (start.parse::<i64>(), end.parse::<i64>()) {
+        // `{01..10}` のように 0 で始まれば桁を揃える
+        let padded = |s: &str| {
+            let digits = s.trim_start_matches('-');
+            digits.len() > 1 && digits.starts_with('0')
+        };
+        let width = if padded(start) || padded(end) { start.len().max(end.len()) } else { 0 };
+        let mut items = Vec::new();
+        let mut n = a;
+        while (a <= b && n <= b) || (a > b && n >= b) {
+            items.push(format!("{n:0width$}"));
+            n = match if a <= b { n.checked_add(step) } else { n.checked_sub(step) } {
+                Some(n) => n,
+                None => break,
+            };
+        }
+        return Some(items);
+    }
+    let mut ends = start.chars().chain(end.chars());
+    let (Some(a), Some(b), None) = (ends.next(), ends.next(), ends.next()) else {
+        return None;
+    };
+    if start.len() != a.len_utf8() || !a.is_ascii_alphabetic() || !b.is_ascii_alphabetic() {
+        return None;
+    }
+    let (a, b, step) = (a as u32, b as u32, step as u32);
+    let mut items = Vec::new();
+    let mut n = a;
+    while (a <= b && n <= b) || (a > b && n >= b) {
+        items.extend(char::from_u32(n).map(String::from));
+        n = match if a <= b { n.checked_add(step) } else { n.checked_sub(step) } {
+            Some(n) => n,
+            None => break,
+        };
+    }
+    Some(items)
+}
+
+// chars[i] から始まるクォートかエスケープの次の位置
+fn skip_quoted(chars: &[char], i: usize) -> usize {
+    let quote = chars[i];
+    if quote == '\\' {
+        return (i + 2).min(chars.len());
+    }
+    let mut j = i + 1;
+    while j < chars.len() {
+        match chars[j] {
+            '\\' if quote != '\'' => j += 1,
+            c if c == quote => return j + 1,
+            _ => {}
+        }
+        j += 1;
+    }
+    chars.len()
+}
+
+// chars[open] の `(` か `{` に対応する閉じ括弧の次の位置
+fn skip_group(chars: &[char], open: usize) -> usize {
+    let close = if chars[open] == '(' { ')' } else { '}' };
+    let mut depth = 0;
+    let mut j = open;
+    while j < chars.len() {
+        match chars[j] {
+            '\'' | '"' | '\\' | '`' => {
+                j = skip_quoted(chars, j);
+                continue;
+            }
+            c if c == chars[open] => depth += 1,
+            c if c == close => {
+                depth -= 1;
+                if depth == 0 {
+                    return j + 1;
+                }
+            }
+            _ => {}
+        }
+        j += 1;
+    }
+    chars.len()
+}
+
+// 展開途中の単語。text は展開結果そのもので、pattern はグロブ用に
+// クォートやエスケープされたメタ文字の前に `\` を付けたもの
+#[derive(Default)]
+struct ExpandedWord {
+    text: String,
+    pattern: String,
+    has_glob: bool,
+    fields: Vec<ExpandedWord>, // `$@` や IFS で区切られた前の単語。最後の単語は text / pattern にある
+    no_params: bool,           // 位置パラメータの無い `$@` を展開した
+    quoted: bool,              // クォートを含む (展開結果が空でも単語として残す)
+    split: bool,               // クォートされていない展開を IFS で単語分割する
+    split_pending: bool,       // 展開結果が IFS の空白で終わった。次の文字からは別の単語
+}
+
+impl ExpandedWord {
+    // ここまでを一つの単語として区切る
+    fn break_field(&mut self) {
+        let field = ExpandedWord {
+            text: std::mem::take(&mut self.text),
+            pattern: std::mem::take(&mut self.pattern),
+            has_glob: std::mem::take(&mut self.has_glob),
+            ..Default::default()
+        };
+        self.fields.push(field);
+    }
+
+    fn into_fields(mut self) -> Vec<ExpandedWord> {
+        let mut fields = std::mem::take(&mut self.fields);
+        fields.push(self);
+        fields
+    }
+
+    fn push(&mut self, c: char, quoted: bool) {
+        if std::mem::take(&mut self.split_pending) && !self.text.is_empty() {
+            self.break_field();
+        }
+        self.text.push(c);
+        if c == '\\' || (quoted && "*?[]".contains(c)) {
+            self.pattern.push('\\');
+        } else if "*?[".contains(c) {
+            self.has_glob = true;
+        }
+        self.pattern.push(c);
+    }
+
+    fn push_str(&mut self, s: &str, quoted: bool) {
+        for c in s.chars() {
+            self.push(c, quoted);
+        }
+    }
+
+    // クォートされていない展開結果を追加する。IFS (未設定なら空白・タブ・改行) で単語に分け、
+    // IFS の空白の並びは一つの区切りとして前後の空白も捨てる。空白以外の IFS の文字は一文字ごとに区切る
+    fn push_split(&mut self, value: &str) {
+        let ifs = env::var("IFS").unwrap_or_else(|_| " \t\n".to_string());
+        if !self.split || ifs.is_empty() {
+            self.push_str(value, false);
+            return;
+        }
+        for c in value.chars() {
+            if !ifs.contains(c) {
+                self.push(c, false);
+            } else if " \t\n".contains(c) {
+                self.split_pending = true;
+            } else {
+                self.split_pending = false;
+                self.break_field();
+            }
+        }
+    }
+}
+
+// グロブのパターンをパスの要素ごとに照合し、一致したパスをバイト順 (nocaseglob なら大文字小文字を
+// 無視した順) に並べて返す。`.` で始まる名前は、パターンの要素も `.` で始まるか dotglob が
+// 有効なときだけ一致する。`.` と `..` はどちらの場合もグロブでは一致しない
+fn glob_paths(pattern: &str, options: &ShellOptions) -> Vec<String> {
+    let nocase = options.nocaseglob;
+    let (mut paths, rest) = match pattern.strip_prefix('/') {
+        Some(rest) => (vec!["/".to_string()], rest),
+        None => (vec![String::new()], pattern),
+    };
+    let components: Vec<&str> = rest.split('/').collect();
+    for (i, component) in components.iter().enumerate() {
+        let last = i + 1 == components.len();
+        let mut next = Vec::new();
+        for base in &paths {
+            let prefix = if base.is_empty() || base.ends_with('/') {
+                base.clone()
+            } else {
+                format!("{base}/")
+            };
+            // `a//b` の空要素や `*/` の末尾
+            if component.is_empty() {
+                next.push(if last { prefix } else { base.clone() });
+                continue;
+            }
+            if !has_glob_meta(component) {
+                let path = prefix + &unescape_glob(component);
+                let meta = fs::symlink_metadata(&path);
+                if meta.is_ok() && (last || Path::new(&path).is_dir()) {
+                    next.push(path);
+                }
+                continue;
+            }
+            let dir = if prefix.is_empty() { "." } else { prefix.as_str() };
+            let Ok(entries) = fs::read_dir(dir) else {
+                continue;
+            };
+            for entry in entries.flatten() {
+                let name = entry.file_name().to_string_lossy().into_owned();
+                if name.starts_with('.') && !component.starts_with('.') && !options.dotglob {
+                    continue;
+                }
+                if glob_match(component, &name, nocase) {
+                    let path = format!("{prefix}{name}");
+                    if last || Path::new(&path).is_dir() {
+                        next.push(path);
+                    }
+                }
+            }
+        }
+        paths = next;
+    }
+    if nocase {
+        paths.sort_by(|a, b| a.to_lowercase().cmp(&b.to_lowercase()).then_with(|| a.cmp(b)));
+    } else {
+        paths.sort();
+    }
+    paths
+}
+
+fn has_glob_meta(pattern: &str) -> bool {
+    let mut chars = pattern.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => {
+                chars.next();
+            }
+            '*' | '?' | '[' => return true,
+            _ => {}
+        }
+    }
+    false
+}
+
+fn unescape_glob(pattern: &str) -> String {
+    let mut out = String::new();
+    let mut chars = pattern.chars();
+    while let Some(c) = chars.next() {
+        out.push(if c == '\\' { chars.next().unwrap_or(c) } else { c });
+    }
+    out
+}
+
+// `*` `?` `[...]` (`[!...]` `[^...]` と範囲指定を含む) と `\` によるエスケープを解釈して照合する
+fn glob_match(pattern: &str, name: &str, nocase: bool) -> bool {
+    let p: Vec<char> = pattern.chars().collect();
+    let n: Vec<char> = name.chars().collect();
+    let eq = |a: char, b: char| {
+        if nocase {
+            a.to_lowercase().eq(b.to_lowercase())
+        } else {
+            a == b
+        }
+    };
+    // 一致しなくなったら直前の `*` に戻り、`*` が吸収する文字を一つ増やしてやり直す
+    let (mut pi, mut ni) = (0, 0);
+    let mut star: Option<(usize, usize)> = None;
+    while ni < n.len() {
+        let step = match p.get(pi) {
+            Some('*') => {
+                star = Some((pi, ni));
+                pi += 1;
+                continue;
+            }
+            Some('?') => Some(pi + 1),
+            Some('[') => match_bracket(&p, pi, n[ni], nocase),
+            Some('\\') if pi + 1 < p.len() => eq(p[pi + 1], n[ni]).then_some(pi + 2),
+            Some(&c) => eq(c, n[ni]).then_some(pi + 1),
+            None => None,
+        };
+        match (step, star) {
+            (Some(next), _) => {
+                pi = next;
+                ni += 1;
+            }
+            (None, Some((sp, sn))) => {
+                pi = sp + 1;
+                ni = sn + 1;
+                star = Some((sp, sn + 1));
+            }
+            (None, None) => return false,
+        }
+    }
+    p[pi..].iter().all(|&c| c == '*')
+}
+
+// p[start] の `[` から始まる括弧式に c が一致すれば、括弧式の次の位置を返す
+fn match_bracket(p: &[char], start: usize, c: char, nocase: bool) -> Option<usize> {
+    let candidates: Vec<char> = if nocase {
+        c.to_lowercase().chain(c.to_uppercase()).collect()
+    } else {
+        vec![c]
+    };
+    let mut i = start + 1;
+    let negate = matches!(p.get(i), Some('!' | '^'));
+    if negate {
+        i += 1;
+    }
+    let mut matched = false;
+    let mut first = true;
+    while let Some(&ch) = p.get(i) {
+        if ch == ']' && !first {
+            return (matched != negate).then_some(i + 1);
+        }
+        first = false;
+        // `[:alpha:]` のような文字クラス
+        if ch == '['
+            && p.get(i + 1) == Some(&':')
+            && let Some(len) = p[i + 2..].windows(2).position(|w| w == [':', ']'])
+        {
+            let class: String = p[i + 2..i + 2 + len].iter().collect();
+            if candidates.iter().any(|&x| char_class_matches(&class, x)) {
+                matched = true;
+            }
+            i += len + 4;
+            continue;
+        }
+        let lo = if ch == '\\' && i + 1 < p.len() {
+            i += 1;
+            p[i]
+        } else {
+            ch
+        };
+        i += 1;
+        let mut hi = lo;
+        if p.get(i) == Some(&'-') && p.get(i + 1).is_some_and(|&h| h != ']') {
+            hi = p[i + 1];
+            i += 2;
+        }
+        if candidates.iter().any(|&x| lo <= x && x <= hi) {
+            matched = true;
+        }
+    }
+    // 閉じていない `[` はただの文字
+    (c == '[').then_some(start + 1)
+}
+
+fn char_class_matches(class: &str, c: char) -> bool {
+    match class {
+        "alpha" => c.is_alphabetic(),
+        "digit" => c.is_ascii_digit(),
+        "alnum" => c.is_alphanumeric(),
+        "upper" => c.is_uppercase(),
+        "lower" => c.is_lowercase(),
+        "space" => c.is_whitespace(),
+        "blank" => c == ' ' || c == '\t',
+        "punct" => c.is_ascii_punctuation(),
+        "xdigit" => c.is_ascii_hexdigit(),
+        "cntrl" => c.is_control(),
+        "print" => !c.is_control(),
+        "graph" => !c.is_control() && !c.is_whitespace(),
+        _ => false,
+    }
+}
+
+// parse_line が残した単語を展開し、クォートとエスケープを取り除く。
+// チルダ展開 (`~`, `~user`) はクォートされていない先頭の `~` のみ対象
+fn expand_word(word: &str) -> String {
+    let fields: Vec<String> = expand_word_parts(word, false).into_fields().into_iter().map(|w| w.text).collect();
+    fields.join(" ")
+}
+
+// split が true ならクォートされていない変数展開とコマンド置換の結果を単語分割する
+fn expand_word_parts(word: &str, split: bool) -> ExpandedWord {
+    let mut out = ExpandedWord { split, ..Default::default() };
+    let mut chars = word.chars().peekable();
+    if let Some(rest) = word.strip_prefix('~') {
+        let (user, tail) = rest.split_at(rest.find('/').unwrap_or(rest.len()));
+        let home = if user.is_empty() {
+            Some(env::var("HOME").unwrap_or_default())
+        } else if user.chars().all(|c| c.is_alphanumeric() || "._-".contains(c)) {
+            user_home_dir(user)
+        } else {
+            None
+        };
+        // 知らないユーザーならそのまま残す
+        if let Some(home) = home {
+            out.push_str(&home, true);
+            chars = tail.chars().peekable();
+        }
+    }
+    while let Some(c) = chars.next() {
+        match c {
+            '\'' => {
+                out.quoted = true;
+                for ch in chars.by_ref() {
+                    if ch == '\'' {
+                        break;
+                    }
+                    out.push(ch, true);
+                }
+            }
+            '"' => {
+                out.quoted = true;
+                while let Some(ch) = chars.next() {
+                    match ch {
+                        '"' => break,
+                        '\\' => match chars.peek() {
+                            Some('\n') => {
+                                chars.next(); // 行継続
+                            }
+                            Some(&n @ ('$' | '`' | '"' | '\\')) => {
+                                out.push(n, true);
+                                chars.next();
+                            }
+                            _ => out.push('\\', true),
+                        },
+                        '$' if take_all_params(&mut chars) => push_all_params(&mut out, true),
+                        '$' => {
+                            let mut value = String::new();
+                            expand_dollar(&mut chars, &mut value);
+                            out.push_str(&value, true);
+                        }
+                        '`' => out.push_str(&backquote_substitution(&mut chars), true),
+                        _ => out.push(ch, true),
+                    }
+                }
+            }
+            '\\' => {
+                if let Some(n) = chars.next() {
+                    out.push(n, true);
+                }
+            }
+            '$' if take_all_params(&mut chars) => push_all_params(&mut out, false),
+            '$' => {
+                let mut value = String::new();
+                expand_dollar(&mut chars, &mut value);
+                out.push_split(&value);
+            }
+            '`' => out.push_split(&backquote_substitution(&mut chars)),
+            _ => out.push(c, false),
+        }
+    }
+    out
+}
+
+// 起動時の端末設定。壊れた状態で子プロセスが終了したときやパニック時に書き戻す
+static SAVED_TERMIOS: OnceCell<libc::termios> = OnceCell::new();
+
+fn save_terminal_modes() {
+    unsafe {
+        let mut termios: libc::termios = std::mem::zeroed();
+        if libc::isatty(libc::STDIN_FILENO) == 1
+            && libc::tcgetattr(libc::STDIN_FILENO, &mut termios) == 0
+        {
+            let _ = SAVED_TERMIOS.set(termios);
+        }
+    }
+    let default_hook = std::panic::take_hook();
+    std::panic::set_hook(Box::new(move |info| {
+        restore_terminal_modes();
+        default_hook(info);
+    }));
+}
+
+fn restore_terminal_modes() {
+    if let Some(termios) = SAVED_TERMIOS.get() {
+        unsafe {
+            libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, termios);
+        }
+    }
+}
+
+// シェルを終了する前の後始末はここにまとめる
+fn exit_shell(code: i32) -> ! {
+    restore_terminal_modes();
+    std::process::exit(code);
+}
+
+const USAGE: &str = "\
+使い方: unko [オプション]
+        unko [オプション] -c SCRIPT [NAME [ARG...]]
+        unko FILE [ARG...]
+
+  -c SCRIPT        SCRIPT を実行して終了する (NAME が $0、ARG が $1 以降になる)
+  FILE             FILE をスクリプトとして実行して終了する (FILE が $0、ARG が $1 以降になる)
+  --rcfile PATH    ~/.unkorc の代わりに PATH を読む
+  --norc           rc ファイルを読まない
+  -o NAME, +o NAME シェルオプション NAME を有効・無効にして起動する (`set -o` と同じ名前)
+  -l, --login      ログインシェルとして /etc/profile と ~/.unko_profile も読む
+  -h, --help       この使い方を表示する
+  -v, --version    バージョンを表示する
+";
+
+// コマンドラインのオプションを左から確かめる。`-c SCRIPT` があればどの位置でもそこで止め、
+// SCRIPT の位置を返す (後ろの引数は `$0`, `$1`, ... になる)。
+// `-o NAME` / `+o NAME` は options に設定する。
+// `--help` と `--version` はここで表示して終わり、知らないオプションならエラーで終わる
+fn check_startup_flags(args: &[String], options: &mut ShellOptions) -> Option<usize> {
+    let mut it = args.iter().skip(1);
+    while let Some(arg) = it.next() {
+        match arg.as_str() {
+            "-h" | "--help" => {
+                print!("{USAGE}");
+                std::process::exit(0);
+            }
+            "-v" | "--version" => {
+                println!("unko {}", env!("CARGO_PKG_VERSION"));
+                std::process::exit(0);
+            }
+            "--norc" | "-l" | "--login" => {}
+            // ファイル名の有無は rc_path が確かめる
+            "--rcfile" => {
+                it.next();
+            }
+            flag @ ("-o" | "+o") => match it.next() {
+                Some(name) if options.set(name, flag == "-o") => {}
+                Some(name) => {
+                    eprintln!("unko: {name}: 無効なオプション名です");
+                    std::process::exit(2);
+                }
+                None => {
+                    eprintln!("unko: {flag} にはオプション名が必要です");
+                    std::process::exit(2);
+                }
+            },
+            "-c" => match it.next() {
+                Some(_) => return Some(args.len() - it.len() - 1),
+                None => {
+                    eprintln!("unko: -c にはスクリプトが必要です");
+                    std::process::exit(2);
+                }
+            },
+            _ => {
+                eprintln!("unko: {arg}: 知らないオプションです");
+                eprintln!("`unko --help` で使い方を表示します");
+                std::process::exit(2);
+            }
+        }
+    }
+    None
+}
+
+// 起動時に読む rc ファイル。`--rcfile PATH` > `UNKO_RCFILE` > `~/.unkorc` の順で決め、
+// `--norc` なら読まない
+fn rc_path(args: &[String]) -> Option<PathBuf> {
+    let mut path = env::var_os("UNKO_RCFILE")
+        .filter(|p| !p.is_empty())
+        .map(PathBuf::from);
+    let mut it = args.iter().skip(1);
+    while let Some(arg) = it.next() {
+        match arg.as_str() {
+            "--norc" => return None,
+            "--rcfile" => match it.next() {
+                Some(p) => path = Some(PathBuf::from(p)),
+                None => {
+                    eprintln!("unko: --rcfile にはファイル名が必要です");
+                    std::process::exit(2);
+                }
+            },
+            _ => {}
+        }
+    }
+    path.or_else(|| dirs::home_dir().map(|home| home.join(".unkorc")))
+}
+
+fn path_was_given(args: &[String]) -> bool {
+    env::var_os("UNKO_RCFILE").is_some_and(|p| !p.is_empty()) || args.iter().any(|a| a == "--rcfile")
+}
+
+// `-l` / `--login` か、login が付ける `-unko` のように argv[0] が `-` で始まればログインシェル
+fn is_login_shell(args: &[String]) -> bool {
+    args.first().is_some_and(|a| a.starts_with('-'))
+        || args.iter().skip(1).any(|a| a == "-l" || a == "--login")
+}
+
+// 起動時や終了時に読むファイルを今のシェルの中で実行する。無くても普通なファイルは report_missing を false にして黙る
+fn run_startup_file(path: &Path, state: &mut ShellState, report_missing: bool) -> rustyline::Result<()> {
+    match fs::read_to_string(path) {
+        Ok(script) => {
+            run_script(&script, state)?;
+        }
+        Err(e) if report_missing => {
+            eprintln!("unko: {}: {e}", path.display());
+        }
+        Err(_) => {}
+    }
+    Ok(())
+}
+
+// ログインシェルが終わるときに ~/.unko_logout を読む。その中で `exit` しても二度は読まない
+fn run_logout_file(state: &mut ShellState) {
+    if !std::mem::take(&mut state.login) {
+        return;
+    }
+    if let Some(home) = dirs::home_dir() {
+        let _ = run_startup_file(&home.join(".unko_logout"), state, false);
+    }
+}
+
+// 履歴展開。`!!` `!n` `!-n` `!prefix` で過去のコマンドを取り出し、
+// `:n` `:^` `:$` `:*` `:n-m` (と省略形の `!^` `!$` `!*`) でその単語を選ぶ。
+// 展開が起きなければ None を返す。シングルクォートの中と `\!` は展開しない
+fn expand_history(input: &str, history: &[String]) -> Result<Option<String>, String> {
+    let mut out = String::new();
+    let mut expanded = false;
+    let mut chars = input.chars().peekable();
+    let mut in_double = false;
+    while let Some(c) = chars.next() {
+        match c {
+            '\'' if !in_double => {
+                let _ = read_quoted(&mut chars, c, 0, &mut out);
+            }
+            '"' => {
+                in_double = !in_double;
+                out.push(c);
+            }
+            '\\' => {
+                out.push(c);
+                if let Some(n) = chars.next() {
+                    out.push(n);
+                }
+            }
+            '!' if chars.peek().is_some_and(|n| !" \t\n=(\"".contains(*n)) => {
+                out.push_str(&history_event(&mut chars, history)?);
+                expanded = true;
+            }
+            _ => out.push(c),
+        }
+    }
+    Ok(expanded.then_some(out))
+}
+
+// `!` の直後から一つのイベント (と単語指定子) を読み、置き換える文字列を返す
+fn history_event(chars: &mut Chars<'_>, history: &[String]) -> Result<String, String> {
+    let mut spec = String::new();
+    let event = match chars.peek() {
+        Some('!') => {
+            spec.push(chars.next().unwrap());
+            history.last()
+        }
+        // `!$` などは直前のコマンドの単語指定子の省略形
+        Some('^' | '$' | '*') => history.last(),
+        Some(&c) if c.is_ascii_digit() || c == '-' => {
+            while let Some(&d) = chars.peek() {
+                if !(d.is_ascii_digit() || (d == '-' && spec.is_empty())) {
+                    break;
+                }
+                spec.push(d);
+                chars.next();
+            }
+            match spec.parse::<i64>() {
+                Ok(n) if n > 0 => history.get(n as usize - 1),
+                Ok(n) if n < 0 => history
+                    .len()
+                    .checked_sub(n.unsigned_abs() as usize)
+                    .and_then(|i| history.get(i)),
+                _ => None,
+            }
+        }
+        _ => {
+            while let Some(&d) = chars.peek() {
+                if d.is_whitespace() || ":;|&<>()'\"".contains(d) {
+                    break;
+                }
+                spec.push(d);
+                chars.next();
+            }
+            history.iter().rev().find(|h| h.starts_with(spec.as_str()))
+        }
+    };
+    let Some(event) = event else {
+        return Err(format!("unko: !{spec}: イベントが見つかりません"));
+    };
+
+    let designator = match chars.peek() {
+        Some(&c @ ('^' | '$' | '*')) if spec.is_empty() => {
+            chars.next();
+            c.to_string()
+        }
+        Some(':') => {
+            chars.next();
+            let mut designator = String::new();
+            while let Some(&d) = chars.peek() {
+                if !(d.is_ascii_digit() || "^$*-".contains(d)) {
+                    break;
+                }
+                designator.push(d);
+                chars.next();
+            }
+            designator
+        }
+        _ => return Ok(event.clone()),
+    };
+
+    let words = parse_line(event)
+        .unwrap_or_else(|_| event.split_whitespace().map(str::to_string).collect());
+    let last = words.len().saturating_sub(1);
+    let index = |s: &str| match s {
+        "^" => Some(1),
+        "$" => Some(last),
+        _ => s.parse::<usize>().ok(),
+    };
+    let range = match designator.as_str() {
+        "*" => Some((1, last)),
+        d if d.ends_with('*') => index(&d[..d.len() - 1]).map(|from| (from, last)),
+        d => match d.split_once('-') {
+            Some(("", to)) => index(to).map(|to| (0, to)),
+            Some((from, to)) => index(from).zip(index(to)),
+            None => index(d).map(|n| (n, n)),
+        },
+    };
+    // `!*` は引数が無ければ空になる
+    if designator == "*" && words.len() < 2 {
+        return Ok(String::new());
+    }
+    let (from, to) = range.unwrap_or((1, 0));
+    match words.get(from..=to) {
+        Some(selected) if from <= to => Ok(selected.join(" ")),
+        _ => Err(format!("unko: !{spec}:{designator}: 単語指定子が不正です")),
+    }
+}
+
+fn main() -> rustyline::Result<()> {
+    let args_vec: Vec<String> = env::args().collect();
+    // `$(...)` の子シェルは親の `$?` を UNKO_LAST_STATUS で受け取る。子が起動するコマンドには渡さない
+    if let Some(status) = env::var("UNKO_LAST_STATUS").ok().and_then(|s| s.parse().ok()) {
+        LAST_STATUS.store(status, Ordering::SeqCst);
+    }
+    unsafe { env::remove_var("UNKO_LAST_STATUS") };
+    // `unko FILE ARG...` はファイルをスクリプトとして実行する。FILE が `$0`、ARG が `$1` 以降になる。
+    // 先頭の `#!` の行はコメントとして読み飛ばされる
+    if let Some(path) = args_vec.get(1).filter(|arg| !arg.starts_with('-')) {
+        let script = match fs::read_to_string(path) {
+            Ok(script) => script,
+            Err(e) => {
+                eprintln!("unko: {path}: {e}");
+                std::process::exit(127);
+            }
+        };
+        *POSITIONAL.write().unwrap() = args_vec[1..].to_vec();
+        run_script(&script, &mut ShellState::default())?;
+        exit_shell(LAST_STATUS.load(Ordering::SeqCst));
+    }
+    let mut options = ShellOptions::default();
+    let script = check_startup_flags(&args_vec, &mut options);
+    *OPTIONS.write().unwrap() = options.clone();
+    if let Some(script) = script {
+        if args_vec.len() > script + 1 {
+            *POSITIONAL.write().unwrap() = args_vec[script + 1..].to_vec();
+        }
+        run_script(&args_vec[script], &mut ShellState { options, ..Default::default() })?;
+        exit_shell(LAST_STATUS.load(Ordering::SeqCst));
+    }
+
+    let config: Config = ConfigBuilder::new()
+        .history_ignore_dups(true)?
+        .completion_type(CompletionType::List)
+        .edit_mode(EditMode::Emacs)
+        .build();
+
+    let mut rl: Editor<ShellHelper, FileHistory> = Editor::with_config(config)?;
+    rl.set_helper(Some(ShellHelper {
+        completer: FilenameCompleter::new(),
+        highlighter: MatchingBracketHighlighter::new(),
+        history: VecDeque::new(),
+        aliases: HashSet::new(),
+    }));
+
+    let mut state = ShellState {
+        options,
+        interactive: true,
+        login: is_login_shell(&args_vec),
+        ..Default::default()
+    };
+
+    save_terminal_modes();
+
+    // ログインシェルなら rc ファイルより先に /etc/profile と ~/.unko_profile を読む
+    if state.login {
+        run_startup_file(Path::new("/etc/profile"), &mut state, false)?;
+        if let Some(home) = dirs::home_dir() {
+            run_startup_file(&home.join(".unko_profile"), &mut state, false)?;
+        }
+    }
+    // 既定の ~/.unkorc が無いのは普通なので、明示されたときだけ知らせる
+    if let Some(path) = rc_path(&args_vec) {
+        run_startup_file(&path, &mut state, path_was_given(&args_vec))?;
+    }
+
+    rl.helper_mut().unwrap().aliases = state.aliases.keys().cloned().collect();
+
+    // rc ファイルで UNKO_HISTFILE や UNKO_HISTSIZE を設定できるよう、履歴はその後で読む
+    let size = history_size();
+    rl.history_mut().set_max_len(size)?;
+    if size > 0 {
+        let mut history = load_history_file(&history_path());
+        history.drain(..history.len().saturating_sub(size));
+        for entry in &history {
+            rl.add_history_entry(entry.line.as_str())?;
+            rl.helper_mut().unwrap().remember(&entry.line);
+        }
+        state.history = history;
+    }
+
+    loop {
+        // 子プロセスが端末を raw モードなどのまま終了していても、起動時の設定に戻してから読む
+        restore_terminal_modes();
+        state.jobs.notify();
+        let prompt = build_prompt(LAST_STATUS.load(Ordering::SeqCst));
+
+        // 閉じていないクォートや行末の `|` / `\` があれば Validator が続きを読ませるので、
+        // 貼り付けた複数行のコマンドもここでは一つの入力として受け取る
+        let input = match rl.readline(&prompt) {
+            Ok(line) => line,
+            Err(ReadlineError::Interrupted) => {
+                println!("^C");
+                LAST_STATUS.store(130, Ordering::SeqCst);
+                continue;
+            }
+            Err(ReadlineError::Eof) => {
+                println!();
+                run_logout_file(&mut state);
+                state.save_history();
+                exit_shell(LAST_STATUS.load(Ordering::SeqCst));
+            }
+            Err(err) => {
+                eprintln!("これもうわかんねぇな…: {err}");
+                exit_shell(1);
+            }
+        };
+
+        if input.trim().is_empty() {
+            continue;
+        }
+
+        // 履歴展開が起きたら展開後の行を表示し、それを実行して履歴にも残す
+        let input = if input.contains('!') {
+            let history: Vec<String> = state.history.iter().map(|e| e.line.clone()).collect();
+            match expand_history(&input, &history) {
+                Ok(Some(expanded)) => {
+                    println!("{expanded}");
+                    expanded
+                }
+                Ok(None) => input,
+                Err(e) => {
+                    eprintln!("{e}");
+                    LAST_STATUS.store(1, Ordering::SeqCst);
+                    continue;
+                }
+            }
+        } else {
+            input
+        };
+
+        // クォートや空白を含め、入力されたままの形で履歴に残す
+        rl.add_history_entry(input.as_str())?;
+        state.add_history(&input);
+        rl.helper_mut().unwrap().remember(&input);
+
+        state.errexit_tripped = false;
+        for line in split_script_lines(&input) {
+            if !line.trim().is_empty() {
+                run_line(&line, &mut state);
+            }
+            if state.errexit_tripped {
+                break;
+            }
+        }
+        let mode = if state.options.vi { EditMode::Vi } else { EditMode::Emacs };
+        rl.set_edit_mode(mode);
+        rl.helper_mut().unwrap().aliases = state.aliases.keys().cloned().collect();
+    }
+}
+
+// 履歴ファイルは UNKO_HISTFILE で変えられ、既定は ~/.unko_history
+fn history_path() -> PathBuf {
+    match env::var_os("UNKO_HISTFILE").filter(|p| !p.is_empty()) {
+        Some(path) => PathBuf::from(path),
+        None => dirs::home_dir()
+            .unwrap_or_else(|| PathBuf::from("."))
+            .join(".unko_history"),
+    }
+}
+
+const DEFAULT_HISTORY_SIZE: usize = 1000;
+
+// 覚えておく履歴の件数は UNKO_HISTSIZE で変えられる。0 なら履歴を読み書きしない
+fn history_size() -> usize {
+    env::var("UNKO_HISTSIZE")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(DEFAULT_HISTORY_SIZE)
+}
+
+// 履歴ファイルは rustyline と同じ `#V2` 形式 (改行と `\` をエスケープ) で、
+// 時刻付きの項目は bash と同じく直前に `#UNIX時刻` の行を置く。
+// 時刻の行と区別できるよう、`#` で始まるコマンドは `\#` と書く
+fn load_history_file(path: &Path) -> Vec<HistoryEntry> {
+    let Ok(content) = fs::read_to_string(path) else {
+        return Vec::new();
+    };
+    let mut lines = content.lines().peekable();
+    let v2 = lines.next_if_eq(&"#V2").is_some();
+    let mut entries = Vec::new();
+    let mut time = None;
+    for line in lines.filter(|l| !l.is_empty()) {
+        if let Some(t) = line.strip_prefix('#').and_then(|t| t.parse().ok()) {
+            time = Some(t);
+            continue;
+        }
+        let line = if v2 { unescape_history_line(line) } else { line.to_string() };
+        entries.push(HistoryEntry { line, time: time.take() });
+    }
+    entries
+}
+
+fn unescape_history_line(line: &str) -> String {
+    let mut out = String::with_capacity(line.len());
+    let mut chars = line.chars();
+    while let Some(c) = chars.next() {
+        match (c, chars.clone().next()) {
+            ('\\', Some('n')) => {
+                chars.next();
+                out.push('\n');
+            }
+            ('\\', Some('\\')) => {
+                chars.next();
+                out.push('\\');
+            }
+            ('\\', Some('#')) if out.is_empty() => {
+                chars.next();
+                out.push('#');
+            }
+            _ => out.push(c),
+        }
+    }
+    out
+}
+
+fn write_history_file(path: &Path, entries: &[HistoryEntry]) -> io::Result<()> {
+    let mut content = String::from("#V2\n");
+    for entry in entries {
+        content.push_str(&history_record(entry));
+    }
+    fs::write(path, content)
+}
+
+fn append_history_file(path: &Path, entry: &HistoryEntry) -> io::Result<()> {
+    use std::io::Write;
+    let mut file = fs::OpenOptions::new().create(true).append(true).open(path)?;
+    let mut content = String::new();
+    if file.metadata()?.len() == 0 {
+        content.push_str("#V2\n");
+    }
+    content.push_str(&history_record(entry));
+    file.write_all(content.as_bytes())
+}
+
+fn history_record(entry: &HistoryEntry) -> String {
+    let mut record = String::new();
+    if let Some(time) = entry.time {
+        record.push_str(&format!("#{time}\n"));
+    }
+    if entry.line.starts_with('#') {
+        record.push('\\');
+    }
+    record.push_str(&entry.line.replace('\\', "\\\\").replace('\n', "\\n"));
+    record.push('\n');
+    record
+}
+
+// `history` は番号付きで履歴を表示する。UNKO_HISTTIMEFORMAT があれば strftime の書式で時刻も出す。
+// `history -w [FILE]` は履歴ファイルに書き出す
+fn builtin_history(argv: &[String], state: &ShellState) -> i32 {
+    let args: Vec<String> = argv[1..].iter().map(|a| expand_word(a)).collect();
+    match args.first().map(String::as_str) {
+        None => {
+            let format = env::var("UNKO_HISTTIMEFORMAT").ok();
+            for (i, entry) in state.history.iter().enumerate() {
+                let time = match (&format, entry.time) {
+                    (Some(format), Some(time)) => local_time_at(time as libc::time_t)
+                        .map(|tm| strftime(format, &tm))
+                        .unwrap_or_default(),
+                    _ => String::new(),
+                };
+                println!("{:5}  {time}{}", i + 1, entry.line);
+            }
+            0
+        }
+        Some("-w") => {
+            let path = args.get(1).map(PathBuf::from).unwrap_or_else(history_path);
+            match write_history_file(&path, &state.history) {
+                Ok(()) => 0,
+                Err(e) => {
+                    eprintln!("history: {}: {e}", path.display());
+                    1
+                }
+            }
+        }
+        Some(arg) => {
+            eprintln!("history: {arg}: 使い方: history [-w [FILE]]");
+            2
+        }
+    }
+}
+
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn note_sigint(_: libc::c_int) {
+    INTERRUPTED.store(true, Ordering::SeqCst);
+}
+
+// f の間だけ SIGINT でシェルが終了しないようにし、受け取ったことを INTERRUPTED に記録する
+fn with_sigint_noted<T>(f: impl FnOnce() -> T) -> T {
+    INTERRUPTED.store(false, Ordering::SeqCst);
+    let previous =
+        unsafe { libc::signal(libc::SIGINT, note_sigint as *const () as libc::sighandler_t) };
+    let result = f();
+    unsafe { libc::signal(libc::SIGINT, previous) };
+    result
+}
+
+// `waitfile PATH [TIMEOUT]` はパスが現れるまで待つ。TIMEOUT 秒 (小数可) を過ぎたら 1、
+// Ctrl-C で中断したら 130 を返す
+fn builtin_waitfile(argv: &[String]) -> i32 {
+    let args: Vec<String> = argv[1..].iter().map(|a| expand_word(a)).collect();
+    let timeout = match args.get(1).map(|t| t.parse::<f64>()) {
+        None => None,
+        Some(Ok(secs)) if secs >= 0.0 && secs.is_finite() => Some(Duration::from_secs_f64(secs)),
+        Some(_) => {
+            eprintln!("waitfile: {}: 秒数を指定してください", args[1]);
+            return 2;
+        }
+    };
+    let (Some(path), true) = (args.first(), args.len() <= 2) else {
+        eprintln!("waitfile: 使い方: waitfile PATH [TIMEOUT]");
+        return 2;
+    };
+
+    let start = Instant::now();
+    with_sigint_noted(|| loop {
+        if Path::new(path).exists() {
+            return 0;
+        }
+        if INTERRUPTED.load(Ordering::SeqCst) {
+            return 130;
+        }
+        if timeout.is_some_and(|t| start.elapsed() >= t) {
+            eprintln!("waitfile: {path}: タイムアウトしました");
+            return 1;
+        }
+        std::thread::sleep(Duration::from_millis(100));
+    })
+}
+
+// SIGTERM を送ってからこの時間待っても終わらなければ SIGKILL を送る
+const TIMEOUT_KILL_AFTER: Duration = Duration::from_secs(1);
+
+// `timeout SEC CMD...` は CMD を別のプロセスグループで実行し、SEC 秒 (s/m/h の単位も可) を
+// 過ぎたらグループごと終了させて 124 を返す。Ctrl-C は CMD のグループに SIGINT として伝える
+fn builtin_timeout(argv: &[String], state: &mut ShellState) -> i32 {
+    let args: Vec<String> = argv[1..].iter().flat_map(|a| expand_arg(a, &state.options)).collect();
+    if args.len() < 2 {
+        eprintln!("timeout: 使い方: timeout SEC CMD [ARG...]");
+        return 125;
+    }
+    let Some(limit) = parse_duration(&args[0]) else {
+        eprintln!("timeout: {}: 時間の指定が不正です", args[0]);
+        return 125;
+    };
+    let program = state.lookup_command(&args[1]).unwrap_or_else(|| args[1].clone());
+    let mut child = match Command::new(&program).args(&args[2..]).process_group(0).spawn() {
+        Ok(child) => child,
+        Err(e) => {
+            eprintln!("timeout: {}: {e}", args[1]);
+            return if e.kind() == io::ErrorKind::NotFound { 127 } else { 126 };
+        }
+    };
+    let pgid = child.id() as libc::pid_t;
+
+    let start = Instant::now();
+    let mut term_sent: Option<Instant> = None;
+    let status = with_sigint_noted(|| loop {
+        match child.try_wait() {
+            Ok(Some(status)) => return Some(status),
+            Ok(None) => {}
+            Err(_) => return None,
+        }
+        if INTERRUPTED.swap(false, Ordering::SeqCst) {
+            unsafe { libc::kill(-pgid, libc::SIGINT) };
+        }
+        match term_sent {
+            None if start.elapsed() >= limit => {
+                unsafe { libc::kill(-pgid, libc::SIGTERM) };
+                term_sent = Some(Instant::now());
+            }
+            Some(sent) if sent.elapsed() >= TIMEOUT_KILL_AFTER => {
+                unsafe { libc::kill(-pgid, libc::SIGKILL) };
+            }
+            _ => {}
+        }
+        std::thread::sleep(Duration::from_millis(20));
+    });
+    match (status, term_sent) {
+        (_, Some(_)) => 124,
+        (Some(status), None) => exit_code(status),
+        (None, None) => 1,
+    }
+}
+
+// `1.5`, `10s`, `2m`, `1h` のような時間の指定
+fn parse_duration(spec: &str) -> Option<Duration> {
+    let (number, unit) = match spec.strip_suffix(['s', 'm', 'h']) {
+        Some(number) => (number, &spec[number.len()..]),
+        None => (spec, "s"),
+    };
+    let secs = number.parse::<f64>().ok()? * match unit {
+        "m" => 60.0,
+        "h" => 3600.0,
+        _ => 1.0,
+    };
+    (secs >= 0.0 && secs.is_finite()).then(|| Duration::from_secs_f64(secs))
+}
+
+type JobId = usize;
+
+// バックグラウンドで起動したパイプライン。pids は各段のプロセスと、回収済みなら waitpid のステータス
+#[derive(Debug)]
+struct Job {
+    id: JobId,
+    pgid: libc::pid_t,
+    pids: Vec<(libc::pid_t, Option<i32>)>,
+    command: String,
+    state: JobState,
+    reported: JobState, // 最後に利用者へ知らせた状態
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum JobState {
+    Running,
+    Stopped,
+    Done(i32), // 最後の段の waitpid のステータス
+}
+
+// recent はジョブ番号を古い順に並べたもの。最後が `%+` (現在のジョブ)、その一つ前が `%-`
+#[derive(Debug, Default)]
+struct JobTable {
+    jobs: Vec<Job>,
+    recent: Vec<JobId>,
+}
+
+impl JobTable {
+    fn add(&mut self, mut job: Job) -> JobId {
+        let id = self.jobs.iter().map(|j| j.id).max().unwrap_or(0) + 1;
+        job.id = id;
+        self.jobs.push(job);
+        self.recent.push(id);
+        id
+    }
+
+    fn get(&self, id: JobId) -> Option<&Job> {
+        self.jobs.iter().find(|j| j.id == id)
+    }
+
+    fn get_mut(&mut self, id: JobId) -> Option<&mut Job> {
+        self.jobs.iter_mut().find(|j| j.id == id)
+    }
+
+    fn remove(&mut self, id: JobId) -> Option<Job> {
+        self.recent.retain(|&r| r != id);
+        let idx = self.jobs.iter().position(|j| j.id == id)?;
+        Some(self.jobs.remove(idx))
+    }
+
+    // fg や bg の対象になったジョブを現在のジョブにする
+    fn touch(&mut self, id: JobId) {
+        self.recent.retain(|&r| r != id);
+        self.recent.push(id);
+    }
+
+    fn current(&self) -> Option<JobId> {
+        self.recent.last().copied()
+    }
+
+    fn previous(&self) -> Option<JobId> {
+        self.recent.iter().rev().nth(1).copied()
+    }
+
+    fn marker(&self, id: JobId) -> char {
+        if self.current() == Some(id) {
+            '+'
+        } else if self.previous() == Some(id) {
+            '-'
+        } else {
+            ' '
+        }
+    }
+
+    // 終了・停止・再開した子プロセスを待たずに回収する
+    fn update(&mut self) {
+        for job in &mut self.jobs {
+            job.poll(false);
+        }
+    }
+
+    // プロンプトを出す前に、終わったり止まったりしたジョブを知らせる。終わったジョブは表から消す
+    fn notify(&mut self) {
+        self.update();
+        let changed: Vec<JobId> =
+            self.jobs.iter().filter(|j| j.state != j.reported).map(|j| j.id).collect();
+        for id in changed {
+            let marker = self.marker(id);
+            let job = self.get_mut(id).unwrap();
+            // 再開したことはわざわざ知らせない
+            if job.state != JobState::Running {
+                println!("[{id}]{marker}  {:<24}{}", job.state_text(), job.command);
+            }
+            job.reported = job.state;
+            if matches!(job.state, JobState::Done(_)) {
+                self.remove(id);
+            }
+        }
+    }
+}
+
+impl Job {
+    // ジョブ番号は表に入れたときに決まる
+    fn new(pids: Vec<libc::pid_t>, command: String) -> Job {
+        Job {
+            id: 0,
+            pgid: pids[0],
+            pids: pids.into_iter().map(|pid| (pid, None)).collect(),
+            command,
+            state: JobState::Running,
+            reported: JobState::Running,
+        }
+    }
+
+    // block なら全ての段が終わるか、どれかが止まるまで待つ
+    fn poll(&mut self, block: bool) {
+        let flags = if block { libc::WUNTRACED } else { libc::WNOHANG | libc::WUNTRACED | libc::WCONTINUED };
+        for (pid, status) in self.pids.iter_mut().filter(|(_, s)| s.is_none()) {
+            let mut raw = 0;
+            let result = loop {
+                let r = unsafe { libc::waitpid(*pid, &mut raw, flags) };
+                if r >= 0 || io::Error::last_os_error().kind() != io::ErrorKind::Interrupted {
+                    break r;
+                }
+            };
+            if result < 0 {
+                // 既に回収されている。ステータスは分からないので失敗扱いにする
+                *status = Some(1 << 8);
+            } else if result == 0 {
+                continue;
+            } else if libc::WIFSTOPPED(raw) {
+                self.state = JobState::Stopped;
+                if block {
+                    return;
+                }
+            } else if libc::WIFCONTINUED(raw) {
+                self.state = JobState::Running;
+            } else {
+                *status = Some(raw);
+            }
+        }
+        if let Some(&(_, Some(raw))) = self.pids.last()
+            && self.pids.iter().all(|(_, s)| s.is_some())
+        {
+            self.state = JobState::Done(raw);
+        }
+    }
+
+    fn state_text(&self) -> String {
+        match self.state {
+            JobState::Running => "Running".to_string(),
+            JobState::Stopped => "Stopped".to_string(),
+            JobState::Done(raw) if libc::WIFSIGNALED(raw) => {
+                let name = unsafe { CStr::from_ptr(libc::strsignal(libc::WTERMSIG(raw))) };
+                name.to_string_lossy().into_owned()
+            }
+            JobState::Done(raw) => match libc::WEXITSTATUS(raw) {
+                0 => "Done".to_string(),
+                code => format!("Exit {code}"),
+            },
+        }
+    }
+}
+
+// 前面のジョブが Ctrl-C で終わったら、`^C` の後にプロンプトが続かないよう改行する
+fn end_line_after_sigint(raw: i32) {
+    if libc::WIFSIGNALED(raw) && libc::WTERMSIG(raw) == libc::SIGINT {
+        println!();
+    }
+}
+
+fn wait_status_code(raw: i32) -> i32 {
+    if libc::WIFSIGNALED(raw) {
+        128 + libc::WTERMSIG(raw)
+    } else {
+        libc::WEXITSTATUS(raw)
+    }
+}
+
+// `%+` `%%` `%` は現在のジョブ、`%-` は一つ前のジョブ、`%N` はジョブ番号、
+// `%string` はコマンドが string で始まるジョブ、`%?string` は string を含むジョブ。
+// 当てはまるジョブが複数あるときは曖昧なので None を返す
+fn resolve_jobspec(spec: &str, jobs: &JobTable) -> Option<JobId> {
+    let rest = spec.strip_prefix('%')?;
+    match rest {
+        "" | "%" | "+" => jobs.current(),
+        "-" => jobs.previous(),
+        _ if rest.bytes().all(|b| b.is_ascii_digit()) => {
+            rest.parse().ok().filter(|&id| jobs.get(id).is_some())
+        }
+        _ => {
+            let mut found = jobs.jobs.iter().filter(|job| match rest.strip_prefix('?') {
+                Some(needle) => job.command.contains(needle),
+                None => job.command.starts_with(rest),
+            });
+            let job = found.next()?;
+            found.next().is_none().then_some(job.id)
+        }
+    }
+}
+
+// fg / bg / disown の対象のジョブ。省略したときは現在のジョブ
+fn job_argument(name: &str, spec: Option<&String>, jobs: &JobTable) -> Option<JobId> {
+    let spec = spec.map(|s| expand_word(s)).unwrap_or_else(|| "%+".to_string());
+    let id = resolve_jobspec(&spec, jobs);
+    if id.is_none() {
+        eprintln!("{name}: {spec}: そのようなジョブはありません");
+    }
+    id
+}
+
+// パイプラインをバックグラウンドで起動し、ジョブ表に登録する
+fn run_background(commands: Vec<CommandInfo>, command: String, state: &mut ShellState) -> i32 {
+    let spawned = spawn_pipeline(commands, state, ProcessGroup::Background(0));
+    reap_in_background(spawned.helpers);
+    let pids = child_pids(spawned.children);
+    let Some(&last) = pids.last() else {
+        return pipeline_status(&spawned.stages, &[], false);
+    };
+    let id = state.jobs.add(Job::new(pids, command));
+    println!("[{id}] {last}");
+    0
+}
+
+// ジョブの子プロセスは waitpid で直接回収するので Child は捨てる (drop しても待ったり殺したりはしない)
+fn child_pids(children: Vec<Child>) -> Vec<libc::pid_t> {
+    children.iter().map(|c| c.id() as libc::pid_t).collect()
+}
+
+// プロセス置換の子は、ジョブとは別に裏のスレッドで待って回収する。FIFO は子が終わった時点で消す。
+// ジョブはまだ動いているので、finish のように FIFO を開いて子を先に進めることはしない
+fn reap_in_background(helpers: Vec<ProcessSub>) {
+    if helpers.is_empty() {
+        return;
+    }
+    std::thread::spawn(move || {
+        for helper in helpers {
+            helper.wait();
+        }
+    });
+}
+
+// 前面で動いているパイプラインのプロセスグループ。シェルに届いた SIGINT をここへ転送する
+static FOREGROUND_PGID: AtomicI32 = AtomicI32::new(0);
+
+extern "C" fn forward_sigint(_: libc::c_int) {
+    let pgid = FOREGROUND_PGID.load(Ordering::SeqCst);
+    if pgid > 0 {
+        unsafe { libc::kill(-pgid, libc::SIGINT) };
+    }
+}
+
+// 対話モードでは前面のパイプラインも独立したプロセスグループで動かし、端末を渡して待つ。
+// Ctrl-C はそのグループの全ての段に届いてシェルには届かず、Ctrl-Z で止まればジョブ表に入る
+fn wait_foreground(spawned: SpawnedPipeline, command: String, state: &mut ShellState) -> i32 {
+    let mut job = Job::new(child_pids(spawned.children), command);
+    FOREGROUND_PGID.store(job.pgid, Ordering::SeqCst);
+    let previous =
+        unsafe { libc::signal(libc::SIGINT, forward_sigint as *const () as libc::sighandler_t) };
+    job.poll(true);
+    unsafe { libc::signal(libc::SIGINT, previous) };
+    FOREGROUND_PGID.store(0, Ordering::SeqCst);
+    set_terminal_pgrp(unsafe { libc::getpgrp() });
+
+    match job.state {
+        JobState::Done(raw) => {
+            for helper in spawned.helpers {
+                helper.finish();
+            }
+            end_line_after_sigint(raw);
+            let statuses: Vec<i32> =
+                job.pids.iter().map(|&(_, status)| wait_status_code(status.unwrap_or(raw))).collect();
+            pipeline_status(&spawned.stages, &statuses, state.options.pipefail)
+        }
+        _ => {
+            reap_in_background(spawned.helpers);
+            job.reported = job.state;
+            let text = format!("{:<24}{}", job.state_text(), job.command);
+            let id = state.jobs.add(job);
+            println!();
+            println!("[{id}]+  {text}");
+            128 + libc::SIGTSTP
+        }
+    }
+}
+
+// 端末の前面のプロセスグループを切り替える。シェルが背面にいる間に呼んでも止まらないよう、
+// その間だけ SIGTTOU を無視する
+fn set_terminal_pgrp(pgid: libc::pid_t) {
+    unsafe {
+        if libc::isatty(0) == 0 {
+            return;
+        }
+        let previous = libc::signal(libc::SIGTTOU, libc::SIG_IGN);
+        libc::tcsetpgrp(0, pgid);
+        libc::signal(libc::SIGTTOU, previous);
+    }
+}
+
+// `jobs [-l|-p] [JOBSPEC...]`。-l は PID も、-p は PID だけを表示する。
+// 終わったジョブは一度表示したら表から消す
+fn builtin_jobs(argv: &[String], state: &mut ShellState) -> i32 {
+    let mut long = false;
+    let mut pids_only = false;
+    let mut specs = Vec::new();
+    for arg in argv[1..].iter().map(|a| expand_word(a)) {
+        match arg.as_str() {
+            "-l" => long = true,
+            "-p" => pids_only = true,
+            _ if arg.starts_with('-') => {
+                eprintln!("jobs: {arg}: 無効なオプションです");
+                eprintln!("jobs: 使い方: jobs [-l|-p] [JOBSPEC...]");
+                return 2;
+            }
+            _ => specs.push(arg),
+        }
+    }
+
+    state.jobs.update();
+    let mut ids = Vec::new();
+    let mut status = 0;
+    if specs.is_empty() {
+        ids.extend(state.jobs.jobs.iter().map(|j| j.id));
+    }
+    for spec in &specs {
+        match resolve_jobspec(spec, &state.jobs) {
+            Some(id) => ids.push(id),
+            None => {
+                eprintln!("jobs: {spec}: そのようなジョブはありません");
+                status = 1;
+            }
+        }
+    }
+
+    for id in ids {
+        let marker = state.jobs.marker(id);
+        let Some(job) = state.jobs.get_mut(id) else { continue };
+        job.reported = job.state;
+        if pids_only {
+            println!("{}", job.pgid);
+            continue;
+        }
+        let suffix = if job.state == JobState::Running { " &" } else { "" };
+        if long {
+            println!("[{id}]{marker} {} {:<24}{}{suffix}", job.pgid, job.state_text(), job.command);
+        } else {
+            println!("[{id}]{marker}  {:<24}{}{suffix}", job.state_text(), job.command);
+        }
+        if matches!(job.state, JobState::Done(_)) {
+            state.jobs.remove(id);
+        }
+    }
+    status
+}
+
+// `fg [JOBSPEC]` はジョブに端末を渡し、止まっていれば再開させて終わるまで待つ
+fn builtin_fg(argv: &[String], state: &mut ShellState) -> i32 {
+    let Some(id) = job_argument("fg", argv.get(1), &state.jobs) else {
+        return 1;
+    };
+    state.jobs.touch(id);
+    let job = state.jobs.get_mut(id).unwrap();
+    println!("{}", job.command);
+    set_terminal_pgrp(job.pgid);
+    unsafe { libc::kill(-job.pgid, libc::SIGCONT) };
+    job.state = JobState::Running;
+    job.poll(true);
+    set_terminal_pgrp(unsafe { libc::getpgrp() });
+
+    match job.state {
+        JobState::Done(raw) => {
+            state.jobs.remove(id);
+            end_line_after_sigint(raw);
+            wait_status_code(raw)
+        }
+        _ => {
+            job.reported = job.state;
+            println!();
+            println!("[{id}]+  {:<24}{}", job.state_text(), job.command);
+            128 + libc::SIGTSTP
+        }
+    }
+}
+
+// `bg [JOBSPEC]` は止まっているジョブをバックグラウンドのまま再開させる
+fn builtin_bg(argv: &[String], state: &mut ShellState) -> i32 {
+    let Some(id) = job_argument("bg", argv.get(1), &state.jobs) else {
+        return 1;
+    };
+    state.jobs.touch(id);
+    let job = state.jobs.get_mut(id).unwrap();
+    if job.state == JobState::Running {
+        eprintln!("bg: ジョブ {id} は既にバックグラウンドで実行中です");
+        return 0;
+    }
+    unsafe { libc::kill(-job.pgid, libc::SIGCONT) };
+    job.state = JobState::Running;
+    job.reported = JobState::Running;
+    println!("[{id}]+ {} &", job.command);
+    0
+}
+
+// `disown [-a] [JOBSPEC...]` はジョブを表から外す。プロセスはそのまま動き続ける
+fn builtin_disown(argv: &[String], state: &mut ShellState) -> i32 {
+    if argv.get(1).is_some_and(|a| a == "-a") {
+        state.jobs = JobTable::default();
+        return 0;
+    }
+    let specs: Vec<Option<&String>> = if argv.len() > 1 { argv[1..].iter().map(Some).collect() } else { vec![None] };
+    let mut status = 0;
+    for spec in specs {
+        match job_argument("disown", spec, &state.jobs) {
+            Some(id) => {
+                state.jobs.remove(id);
+            }
+            None => status = 1,
+        }
+    }
+    status
+}
+
+const SIGNALS: &[(&str, libc::c_int)] = &[
+    ("HUP", libc::SIGHUP),
+    ("INT", libc::SIGINT),
+    ("QUIT", libc::SIGQUIT),
+    ("KILL", libc::SIGKILL),
+    ("USR1", libc::SIGUSR1),
+    ("USR2", libc::SIGUSR2),
+    ("PIPE", libc::SIGPIPE),
+    ("ALRM", libc::SIGALRM),
+    ("TERM", libc::SIGTERM),
+    ("CONT", libc::SIGCONT),
+    ("STOP", libc::SIGSTOP),
+    ("TSTP", libc::SIGTSTP),
+    ("TTIN", libc::SIGTTIN),
+    ("TTOU", libc::SIGTTOU),
+    ("WINCH", libc::SIGWINCH),
+];
+
+// `9`、`KILL`、`SIGKILL` (大文字小文字は問わない) のどれでもシグナルを指定できる
+fn parse_signal(spec: &str) -> Option<libc::c_int> {
+    if let Ok(n) = spec.parse::<libc::c_int>() {
+        return (0..=64).contains(&n).then_some(n);
+    }
+    let upper = spec.to_ascii_uppercase();
+    let name = upper.strip_prefix("SIG").unwrap_or(&upper);
+    SIGNALS.iter().find(|(n, _)| *n == name).map(|&(_, sig)| sig)
+}
+
+// `kill [-s SIG | -SIG] (PID | JOBSPEC)...`、`kill -l` でシグナルの一覧。
+// ジョブにはプロセスグループごと送る
+fn builtin_kill(argv: &[String], state: &mut ShellState) -> i32 {
+    let args: Vec<String> = argv[1..].iter().map(|a| expand_word(a)).collect();
+    let mut signal = libc::SIGTERM;
+    let mut rest = &args[..];
+    match rest.first().map(String::as_str) {
+        Some("-l") => {
+            for (name, sig) in SIGNALS {
+                println!("{sig:2}) SIG{name}");
+            }
+            return 0;
+        }
+        Some("-s") => {
+            let Some(sig) = rest.get(1).and_then(|s| parse_signal(s)) else {
+                eprintln!("kill: 無効なシグナルです: {}", rest.get(1).map_or("", String::as_str));
+                return 2;
+            };
+            signal = sig;
+            rest = &rest[2..];
+        }
+        Some(spec) if spec.starts_with('-') && spec.len() > 1 => {
+            let Some(sig) = parse_signal(&spec[1..]) else {
+                eprintln!("kill: {spec}: 無効なシグナルです");
+                return 2;
+            };
+            signal = sig;
+            rest = &rest[1..];
+        }
+        _ => {}
+    }
+    if rest.is_empty() {
+        eprintln!("kill: 使い方: kill [-s SIG | -SIG] (PID | JOBSPEC)...");
+        return 2;
+    }
+
+    let mut status = 0;
+    for target in rest {
+        // 止まっているジョブは SIGTERM や SIGHUP を受け取れるよう、送った後で再開させる
+        let (pid, resume) = if target.starts_with('%') {
+            match resolve_jobspec(target, &state.jobs).and_then(|id| state.jobs.get(id)) {
+                Some(job) => (-job.pgid, job.state == JobState::Stopped),
+                None => {
+                    eprintln!("kill: {target}: そのようなジョブはありません");
+                    status = 1;
+                    continue;
+                }
+            }
+        } else {
+            match target.parse::<libc::pid_t>() {
+                Ok(pid) => (pid, false),
+                Err(_) => {
+                    eprintln!("kill: {target}: PID かジョブ指定を指定してください");
+                    status = 1;
+                    continue;
+                }
+            }
+        };
+        if unsafe { libc::kill(pid, signal) } != 0 {
+            eprintln!("kill: ({target}) - {}", io::Error::last_os_error());
+            status = 1;
+        } else if resume && matches!(signal, libc::SIGTERM | libc::SIGHUP) {
+            unsafe { libc::kill(pid, libc::SIGCONT) };
+        }
+    }
+    status
+}
+
+// `wait [PID | JOBSPEC...]`。引数がなければ全てのジョブが終わるまで待つ。
+// 最後に指定したものの終了ステータスを返し、Ctrl-C で中断したら 130 を返す
+fn builtin_wait(argv: &[String], state: &mut ShellState) -> i32 {
+    let args: Vec<String> = argv[1..].iter().map(|a| expand_word(a)).collect();
+    // (ジョブ番号, 終了ステータスを返すプロセス。None ならジョブの最後の段)
+    let mut targets: Vec<(JobId, Option<libc::pid_t>)> = Vec::new();
+    let mut status = 0;
+    if args.is_empty() {
+        targets.extend(state.jobs.jobs.iter().map(|j| (j.id, None)));
+    }
+    for arg in &args {
+        let target = if arg.starts_with('%') {
+            resolve_jobspec(arg, &state.jobs).map(|id| (id, None))
+        } else {
+            arg.parse::<libc::pid_t>().ok().and_then(|pid| {
+                let job = state.jobs.jobs.iter().find(|j| j.pids.iter().any(|&(p, _)| p == pid))?;
+                Some((job.id, Some(pid)))
+            })
+        };
+        match target {
+            Some(target) => targets.push(target),
+            None => {
+                eprintln!("wait: {arg}: このシェルのジョブではありません");
+                status = 127;
+            }
+        }
+    }
+
+    with_sigint_noted(|| {
+        for (id, pid) in targets {
+            let Some(job) = state.jobs.get_mut(id) else { continue };
+            // 止まっているジョブは再開されるまで終わらないので待たない
+            while !matches!(job.state, JobState::Done(_) | JobState::Stopped) {
+                if INTERRUPTED.load(Ordering::SeqCst) {
+                    return 130;
+                }
+                std::thread::sleep(Duration::from_millis(20));
+                job.poll(false);
+            }
+            let raw = match pid {
+                Some(pid) => job.pids.iter().find(|&&(p, _)| p == pid).and_then(|&(_, s)| s),
+                None => match job.state {
+                    JobState::Done(raw) => Some(raw),
+                    _ => None,
+                },
+            };
+            status = raw.map_or(128 + libc::SIGTSTP, wait_status_code);
+            if matches!(job.state, JobState::Done(_)) {
+                state.jobs.remove(id);
+            }
+        }
+        status
+    })
+}
+
+// 直前に実行したコマンドの終了ステータス。`$?` で展開する
+static LAST_STATUS: AtomicI32 = AtomicI32::new(0);
+
+// nounset で未設定の変数を展開した。展開した側のコマンドは実行せずに終了ステータス 1 にする
+static UNSET_EXPANDED: AtomicBool = AtomicBool::new(false);
+
+// 展開の途中で読むシェルオプション。展開する関数は ShellState を受け取らないので、
+// 起動時と `set` で state.options を変えるたびにここへ写す
+static OPTIONS: Lazy<RwLock<ShellOptions>> = Lazy::new(Default::default);
+
+// 位置パラメータ。先頭が `$0`、続きが `$1` 以降。`unko -c SCRIPT NAME ARG...` で NAME と ARG が入る
+static POSITIONAL: Lazy<RwLock<Vec<String>>> = Lazy::new(|| RwLock::new(env::args().take(1).collect()));
+
+// 一つのコマンドラインを解析して実行し、終了ステータスを返す。
+// `;` と `&` で区切られたコマンドは順に実行する (`&` の前はバックグラウンドで起動する)。
+// `&&` の右は直前のコマンドが成功したときだけ、`||` の右は失敗したときだけ実行する
+fn run_line(line: &str, state: &mut ShellState) -> Flow {
+    let tokens = match parse_line(line).and_then(|t| expand_aliases(t, &state.aliases)) {
+        Ok(tokens) if tokens.is_empty() => return Flow::Normal(0),
+        Ok(tokens) => tokens,
+        Err(e) => {
+            eprintln!("{e}");
+            LAST_STATUS.store(1, Ordering::SeqCst);
+            return Flow::Normal(1);
+        }
+    };
+    run_tokens(&tokens, state)
+}
+
+// コマンドを実行した後の制御の流れ。`break` / `continue` はループに届くまで呼び出し元へ返していき、
+// その間に残っているコマンドは実行しない
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Flow {
+    Normal(i32),     // 終了ステータス
+    Break(usize),    // 抜けるループの段数
+    Continue(usize), // 次の周回に進むループが何段外側か (1 なら一番内側)
+}
+
+impl Flow {
+    fn status(self) -> i32 {
+        match self {
+            Flow::Normal(status) => status,
+            Flow::Break(_) | Flow::Continue(_) => 0,
+        }
+    }
+}
+
+// 解析済みのトークン列を実行する。複合コマンドの本体もここを通す
+fn run_tokens(tokens: &[String], state: &mut ShellState) -> Flow {
+    let lists = match split_lists(tokens)
+        .and_then(|lists| lists.into_iter().map(split_and_or).collect::<Result<Vec<_>, _>>())
+    {
+        Ok(lists) => lists,
+        Err(op) => {
+            eprintln!("構文エラー: `{op}` の近くに構文エラーがあります");
+            LAST_STATUS.store(2, Ordering::SeqCst);
+            return Flow::Normal(2);
+        }
+    };
+
+    let mut status = 0;
+    for list in lists {
+        let last = list.len() - 1;
+        for (i, (op, command)) in list.into_iter().enumerate() {
+            let run = match op {
+                Some("&&") => status == 0,
+                Some(_) => status != 0,
+                None => true,
+            };
+            if run {
+                let flow = run_command(command, state);
+                status = flow.status();
+                LAST_STATUS.store(status, Ordering::SeqCst);
+                if flow != Flow::Normal(status) {
+                    return flow;
+                }
+                // `&&` / `||` の左側で失敗しても errexit では止めない
+                if i == last && status != 0 && state.options.errexit {
+                    if !state.interactive {
+                        exit_shell(status);
+                    }
+                    state.errexit_tripped = true;
+                    return flow;
+                }
+            }
+        }
+    }
+    Flow::Normal(status)
+}
+
+// 複合コマンドを始める予約語と、それを閉じる予約語
+const COMPOUND_OPENERS: &[&str] = &["for", "while", "until", "if"];
+const COMPOUND_CLOSERS: &[&str] = &["done", "fi"];
+
+// 各トークンがコマンドの位置にあるか。予約語や `[[` はコマンドの位置でだけ意味を持つので、
+// `echo for` や `type [[` の引数は数えない
+fn command_positions(tokens: &[String]) -> Vec<bool> {
+    let mut positions: Vec<bool> = Vec::with_capacity(tokens.len());
+    for i in 0..tokens.len() {
+        positions.push(i == 0 || starts_command_after(&tokens[i - 1], positions[i - 1]));
+    }
+    positions
+}
+
+// prev の次のトークンがコマンドの位置になるか。予約語はそれ自体がコマンドの位置にあるときだけ数える
+fn starts_command_after(prev: &str, prev_at_command: bool) -> bool {
+    matches!(prev, "(" | ";" | "&" | "&&" | "||" | "|" | "!")
+        || (prev_at_command && matches!(prev, "do" | "while" | "until" | "if" | "then" | "elif" | "else"))
+}
+
+// 各トークンが括弧・`[[ ... ]]`・複合コマンドの何重の内側にあるか
+fn nesting_depths(tokens: &[String]) -> Vec<usize> {
+    let mut depth = 0usize;
+    tokens
+        .iter()
+        .zip(command_positions(tokens))
+        .map(|(token, at_command)| {
+            match token.as_str() {
+                "(" => depth += 1,
+                "[[" if at_command => depth += 1,
+                ")" | "]]" => depth = depth.saturating_sub(1),
+                word if at_command && COMPOUND_OPENERS.contains(&word) => depth += 1,
+                word if at_command && COMPOUND_CLOSERS.contains(&word) => depth = depth.saturating_sub(1),
+                _ => {}
+            }
+            depth
+        })
+        .collect()
+}
+
+// 閉じていない複合コマンドがあるか (対話モードやスクリプトで次の行も読む)
+fn in_compound(tokens: &[String]) -> bool {
+    let mut depth = 0usize;
+    for (token, at_command) in tokens.iter().zip(command_positions(tokens)) {
+        if at_command && COMPOUND_OPENERS.contains(&token.as_str()) {
+            depth += 1;
+        } else if at_command && COMPOUND_CLOSERS.contains(&token.as_str()) {
+            depth = depth.saturating_sub(1);
+        }
+    }
+    depth > 0
+}
+
+// トークン列を `;` と `&` で区切る。`&` はバックグラウンド実行の印としてその前のコマンドに残す。
+// 括弧や `[[ ... ]]`、複合コマンドの中では区切らない。空のコマンドがあればその区切りを返す
+fn split_lists(tokens: &[String]) -> Result<Vec<&[String]>, &str> {
+    let mut lists = Vec::new();
+    let mut start = 0;
+    let depths = nesting_depths(tokens);
+    for (i, token) in tokens.iter().enumerate() {
+        match token.as_str() {
+            ";" | "&" if depths[i] == 0 => {
+                if i == start {
+                    return Err(token);
+                }
+                let end = if token == "&" { i + 1 } else { i };
+                lists.push(&tokens[start..end]);
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    if start < tokens.len() {
+        lists.push(&tokens[start..]);
+    }
+    Ok(lists)
+}
+
+type AndOrList<'a> = Vec<(Option<&'a str>, &'a [String])>;
+
+// トークン列を `&&` / `||` で区切り、(直前の演算子, コマンド) の並びにする。
+// 括弧や `[[ ... ]]`、複合コマンドの中の演算子では区切らない。空のコマンドがあればその演算子を返す
+fn split_and_or(tokens: &[String]) -> Result<AndOrList<'_>, &str> {
+    let mut list = Vec::new();
+    let mut op = None;
+    let mut start = 0;
+    let depths = nesting_depths(tokens);
+    for (i, token) in tokens.iter().enumerate() {
+        match token.as_str() {
+            "&&" | "||" if depths[i] == 0 => {
+                if i == start {
+                    return Err(token);
+                }
+                list.push((op, &tokens[start..i]));
+                op = Some(token.as_str());
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    if start == tokens.len() {
+        return Err(op.unwrap_or("newline"));
+    }
+    list.push((op, &tokens[start..]));
+    Ok(list)
+}
+
+// `&&` / `||` を含まない一つのコマンド (パイプライン) を実行する
+fn run_command(tokens: &[String], state: &mut ShellState) -> Flow {
+    // 行末の `&` はバックグラウンド実行 (それ以外の位置の `&` は split_lists が区切る)
+    let background = tokens.last().is_some_and(|t| t == "&");
+    let tokens = if background { &tokens[..tokens.len() - 1] } else { tokens };
+    // 複合コマンドの後ろにパイプが続くなら、パイプラインの段として実行する
+    if tokens.first().is_some_and(|t| COMPOUND_OPENERS.contains(&t.as_str())) && split_pipeline(tokens).len() == 1 {
+        if background {
+            eprintln!("エラー: '{}' はバックグラウンドでは実行できません。", tokens[0]);
+            return Flow::Normal(1);
+        }
+        return run_compound(tokens, state);
+    }
+    if tokens.is_empty() || tokens.iter().any(|t| t == "&") {
+        eprintln!("構文エラー: `&` の近くに構文エラーがあります");
+        return Flow::Normal(2);
+    }
+
+    UNSET_EXPANDED.store(false, Ordering::SeqCst);
+    let first_cmd = tokens.first().map(String::as_str).unwrap_or("");
+    if background && (first_cmd.starts_with("((") || first_cmd == "[[" || builtin_kind(first_cmd) == Some(BuiltinKind::Special)) {
+        eprintln!("エラー: '{}' はバックグラウンドでは実行できません。", first_cmd);
+        return Flow::Normal(1);
+    }
+    if let Some(expr) = first_cmd.strip_prefix("((").and_then(|t| t.strip_suffix("))")) {
+        if tokens.len() > 1 {
+            eprintln!("構文エラー: `{}` の近くに構文エラーがあります", tokens[1]);
+            return Flow::Normal(2);
+        }
+        let value = eval_arith(&expand_word(expr));
+        return Flow::Normal(match value {
+            _ if UNSET_EXPANDED.swap(false, Ordering::SeqCst) => 1,
+            Ok(0) => 1,
+            Ok(_) => 0,
+            Err(e) => {
+                eprintln!("unko: (({expr})): {e}");
+                1
+            }
+        });
+    }
+    if first_cmd == "[[" {
+        if tokens.last().is_none_or(|t| t != "]]") {
+            eprintln!("構文エラー: `[[` に対応する `]]` がありません。");
+            return Flow::Normal(2);
+        }
+        let result = eval_conditional(&tokens[1..tokens.len() - 1]);
+        return Flow::Normal(match result {
+            _ if UNSET_EXPANDED.swap(false, Ordering::SeqCst) => 1,
+            Ok(true) => 0,
+            Ok(false) => 1,
+            Err(e) => {
+                eprintln!("unko: [[: {e}");
+                2
+            }
+        });
+    }
+    if builtin_kind(first_cmd) == Some(BuiltinKind::Special) && !lists_exports(tokens) {
+        if tokens.contains(&"|".to_string()) {
+            eprintln!("エラー: '{}' はパイプラインでは使用できません。", first_cmd);
+            return Flow::Normal(1);
+        }
+        if tokens.iter().any(|t| REDIRECT_OPS.contains(&t.as_str())) {
+            eprintln!("エラー: '{}' はリダイレクションをサポートしていません。", first_cmd);
+            return Flow::Normal(1);
+        }
+        // 組み込みコマンドは引数を一つずつ expand_word で展開するので、同じ形に展開して表示する。
+        // nounset なら実行する前に未設定の変数が無いか確かめる
+        if state.options.xtrace || state.options.nounset {
+            let words: Vec<String> = tokens.iter().map(|t| expand_word(t)).collect();
+            if UNSET_EXPANDED.swap(false, Ordering::SeqCst) {
+                return Flow::Normal(1);
+            }
+            if state.options.xtrace {
+                trace_command(&words);
+            }
+        }
+        if matches!(first_cmd, "break" | "continue") {
+            return builtin_loop_control(tokens, state);
+        }
+        // BUILTINS に載っている特殊な組み込みコマンドは try_builtin_special が実行する。
+        // 二つの一覧がずれていてもシェルは落とさない
+        let status = try_builtin_special(tokens, state).unwrap_or_else(|| {
+            eprintln!("エラー: '{first_cmd}' を実行する処理がありません。");
+            1
+        });
+        return Flow::Normal(status);
+    }
+
+    Flow::Normal(match parse_commands(tokens) {
+        Ok(pipeline) if background => run_background(pipeline, tokens.join(" "), state),
+        Ok(pipeline) => run_pipeline(pipeline, tokens.join(" "), state),
+        Err(e) => {
+            eprintln!("エラー: {}", e);
+            1
+        }
+    })
+}
+
+// 予約語で始まる複合コマンドを実行する
+fn run_compound(tokens: &[String], state: &mut ShellState) -> Flow {
+    match tokens[0].as_str() {
+        "for" => run_for(tokens, state),
+        "while" | "until" => run_while(tokens, state),
+        "if" => run_if(tokens, state),
+        _ => unreachable!(),
+    }
+}
+
+// tokens[start..] の中で、入れ子の複合コマンドの外にある予約語 keywords のうち最初のものの位置
+fn find_keyword(tokens: &[String], start: usize, keywords: &[&str]) -> Option<usize> {
+    let positions = command_positions(tokens);
+    let mut depth = 0usize;
+    for i in start..tokens.len() {
+        let word = tokens[i].as_str();
+        if !positions[i] {
+            continue;
+        }
+        if depth == 0 && keywords.contains(&word) {
+            return Some(i);
+        }
+        if COMPOUND_OPENERS.contains(&word) {
+            depth += 1;
+        } else if COMPOUND_CLOSERS.contains(&word) {
+            depth = depth.saturating_sub(1);
+        }
+    }
+    None
+}
+
+// 複合コマンドの中のコマンド列。改行から来た前後の `;` は取り除く
+fn compound_body(tokens: &[String]) -> &[String] {
+    let start = tokens.iter().position(|t| t != ";").unwrap_or(tokens.len());
+    let end = tokens.iter().rposition(|t| t != ";").map_or(start, |i| i + 1);
+    &tokens[start..end.max(start)]
+}
+
+// `for NAME [in WORD...]; do BODY; done`。WORD はループの前に一度だけ展開し、一つずつ NAME に入れて BODY を実行する
+fn run_for(tokens: &[String], state: &mut ShellState) -> Flow {
+    let Some(name) = tokens.get(1).filter(|name| is_valid_name(name)) else {
+        eprintln!("構文エラー: `for` の後には変数名が必要です");
+        return Flow::Normal(2);
+    };
+    let mut i = 2;
+    let mut words = Vec::new();
+    if tokens.get(i).is_some_and(|t| t == "in") {
+        i += 1;
+        while let Some(word) = tokens.get(i).filter(|t| *t != ";" && *t != "do") {
+            words.extend(expand_arg(word, &state.options));
+            i += 1;
+        }
+        if UNSET_EXPANDED.swap(false, Ordering::SeqCst) {
+            return Flow::Normal(1);
+        }
+    }
+    if tokens.get(i).is_some_and(|t| t == ";") {
+        i += 1;
+    }
+    if tokens.get(i).is_none_or(|t| t != "do") {
+        eprintln!("構文エラー: `for` に `do` がありません");
+        return Flow::Normal(2);
+    }
+    let Some(done) = find_keyword(tokens, i + 1, &["done"]) else {
+        eprintln!("構文エラー: `do` に対応する `done` がありません");
+        return Flow::Normal(2);
+    };
+    if let Some(extra) = tokens.get(done + 1) {
+        eprintln!("構文エラー: `{extra}` の近くに構文エラーがあります");
+        return Flow::Normal(2);
+    }
+    let body = &tokens[i + 1..done];
+    let mut flow = Flow::Normal(0);
+    state.loop_depth += 1;
+    for word in words {
+        unsafe { env::set_var(name, word) };
+        match next_iteration(run_branch(body, state), state) {
+            Ok(status) => flow = Flow::Normal(status),
+            Err(exit) => {
+                flow = exit;
+                break;
+            }
+        }
+    }
+    state.loop_depth -= 1;
+    flow
+}
+
+// ループ本体を一回実行した結果から、次の周回に進むか (Ok) ループを抜けるか (Err) を決める。
+// Ok は終了ステータス、Err はループの外に返す流れ。Ctrl-C で止まったコマンドがあればループごと抜ける
+fn next_iteration(flow: Flow, state: &ShellState) -> Result<i32, Flow> {
+    match flow {
+        Flow::Break(1) => Err(Flow::Normal(0)),
+        Flow::Break(n) => Err(Flow::Break(n - 1)),
+        Flow::Continue(1) => Ok(0),
+        Flow::Continue(n) => Err(Flow::Continue(n - 1)),
+        Flow::Normal(status) if state.errexit_tripped || status == 128 + libc::SIGINT => Err(flow),
+        Flow::Normal(status) => Ok(status),
+    }
+}
+
+// `while COND; do BODY; done` は COND が成功する間、`until` は失敗する間 BODY を繰り返す。
+// COND は毎回実行し直す。止めるには Ctrl-C
+fn run_while(tokens: &[String], state: &mut ShellState) -> Flow {
+    let until = tokens[0] == "until";
+    let Some(done) = find_keyword(tokens, 1, &["done"]) else {
+        eprintln!("構文エラー: `{}` に対応する `done` がありません", tokens[0]);
+        return Flow::Normal(2);
+    };
+    if let Some(extra) = tokens.get(done + 1) {
+        eprintln!("構文エラー: `{extra}` の近くに構文エラーがあります");
+        return Flow::Normal(2);
+    }
+    let cond = find_keyword(tokens, 1, &["do"])
+        .filter(|&do_at| do_at < done)
+        .map(|do_at| (compound_body(&tokens[1..do_at]), do_at));
+    let Some((cond, do_at)) = cond.filter(|(cond, _)| !cond.is_empty()) else {
+        eprintln!("構文エラー: `{}` に `do` がありません", tokens[0]);
+        return Flow::Normal(2);
+    };
+    let body = &tokens[do_at + 1..done];
+    let mut flow = Flow::Normal(0);
+    state.loop_depth += 1;
+    loop {
+        let errexit = std::mem::replace(&mut state.options.errexit, false);
+        let cond = run_tokens(cond, state);
+        state.options.errexit = errexit;
+        match next_iteration(cond, state) {
+            Ok(status) if (status == 0) != until => {}
+            Ok(_) => break,
+            Err(exit) => {
+                flow = exit;
+                break;
+            }
+        }
+        match next_iteration(run_branch(body, state), state) {
+            Ok(status) => flow = Flow::Normal(status),
+            Err(exit) => {
+                flow = exit;
+                break;
+            }
+        }
+    }
+    state.loop_depth -= 1;
+    flow
+}
+
+// `if COND; then BODY; [elif COND; then BODY;]... [else BODY;] fi`。
+// 終了ステータスが 0 になった最初の COND の BODY を実行する。COND の失敗では errexit で止めない
+fn run_if(tokens: &[String], state: &mut ShellState) -> Flow {
+    let Some(fi) = find_keyword(tokens, 1, &["fi"]) else {
+        eprintln!("構文エラー: `if` に対応する `fi` がありません");
+        return Flow::Normal(2);
+    };
+    if let Some(extra) = tokens.get(fi + 1) {
+        eprintln!("構文エラー: `{extra}` の近くに構文エラーがあります");
+        return Flow::Normal(2);
+    }
+    let mut start = 1;
+    loop {
+        let Some(then) = find_keyword(tokens, start, &["then"]) else {
+            eprintln!("構文エラー: `if` に `then` がありません");
+            return Flow::Normal(2);
+        };
+        let next = find_keyword(tokens, then + 1, &["elif", "else", "fi"]).unwrap_or(fi);
+        let cond = compound_body(&tokens[start..then]);
+        if cond.is_empty() {
+            eprintln!("構文エラー: `then` の近くに構文エラーがあります");
+            return Flow::Normal(2);
+        }
+        let errexit = std::mem::replace(&mut state.options.errexit, false);
+        let flow = run_tokens(cond, state);
+        state.options.errexit = errexit;
+        if flow != Flow::Normal(flow.status()) {
+            return flow;
+        }
+        if flow.status() == 0 {
+            return run_branch(&tokens[then + 1..next], state);
+        }
+        match tokens[next].as_str() {
+            "elif" => start = next + 1,
+            "else" => return run_branch(&tokens[next + 1..fi], state),
+            _ => return Flow::Normal(0),
+        }
+    }
+}
+
+fn run_branch(tokens: &[String], state: &mut ShellState) -> Flow {
+    let body = compound_body(tokens);
+    if body.is_empty() { Flow::Normal(0) } else { run_tokens(body, state) }
+}
+
+// `break [N]` / `continue [N]`。N 段外側のループまで流れを返す。ループの外では警告だけ出して何もしない
+fn builtin_loop_control(argv: &[String], state: &ShellState) -> Flow {
+    let levels = match argv.get(1).map(|a| expand_word(a)) {
+        Some(arg) => match arg.parse::<usize>() {
+            Ok(n) if n > 0 => n,
+            _ => {
+                eprintln!("{}: {arg}: ループの段数は 1 以上の整数で指定してください", argv[0]);
+                return Flow::Normal(1);
+            }
+        },
+        None => 1,
+    };
+    if argv.len() > 2 {
+        eprintln!("{}: 引数が多すぎます", argv[0]);
+        return Flow::Normal(1);
+    }
+    if state.loop_depth == 0 {
+        eprintln!("{}: ループの中でしか意味がないので無視します", argv[0]);
+        return Flow::Normal(0);
+    }
+    // ループの段数より大きければ一番外側のループまで
+    let levels = levels.min(state.loop_depth);
+    if argv[0] == "break" { Flow::Break(levels) } else { Flow::Continue(levels) }
+}
+
+// `[[ ... ]]` の中身を評価する。単語は展開するが、単語分割やグロブ展開はしない。
+// `==` / `!=` の右辺はグロブのパターン、`=~` の右辺は正規表現として照合する
+fn eval_conditional(tokens: &[String]) -> Result<bool, String> {
+    let mut parser = CondParser { tokens, pos: 0 };
+    let result = parser.or(true)?;
+    match parser.peek() {
+        Some(token) => Err(format!("`{token}` の近くに構文エラーがあります")),
+        None => Ok(result),
+    }
+}
+
+// 再帰下降で読みながら評価する。eval が false の部分は `&&` / `||` で飛ばされる側なので、
+// 構文だけ確かめて展開 (コマンド置換など) はしない
+struct CondParser<'a> {
+    tokens: &'a [String],
+    pos: usize,
+}
+
+impl<'a> CondParser<'a> {
+    fn peek(&self) -> Option<&'a str> {
+        self.tokens.get(self.pos).map(String::as_str)
+    }
+
+    fn next(&mut self) -> Result<&'a str, String> {
+        let token = self.peek().ok_or("式が途中で終わっています")?;
+        self.pos += 1;
+        Ok(token)
+    }
+
+    fn or(&mut self, eval: bool) -> Result<bool, String> {
+        let mut value = self.and(eval)?;
+        while self.peek() == Some("||") {
+            self.pos += 1;
+            value |= self.and(eval && !value)?;
+        }
+        Ok(value)
+    }
+
+    fn and(&mut self, eval: bool) -> Result<bool, String> {
+        let mut value = self.not(eval)?;
+        while self.peek() == Some("&&") {
+            self.pos += 1;
+            value &= self.not(eval && value)?;
+        }
+        Ok(value)
+    }
+
+    fn not(&mut self, eval: bool) -> Result<bool, String> {
+        if self.peek() == Some("!") {
+            self.pos += 1;
+            return Ok(!self.not(eval)?);
+        }
+        self.primary(eval)
+    }
+
+    fn primary(&mut self, eval: bool) -> Result<bool, String> {
+        let token = self.next()?;
+        if token == "(" {
+            let value = self.or(eval)?;
+            return match self.next()? {
+                ")" => Ok(value),
+                other => Err(format!("`)` が必要ですが `{other}` がありました")),
+            };
+        }
+        let is_unary = matches!(
+            token,
+            "-e" | "-f" | "-d" | "-s" | "-r" | "-w" | "-x" | "-L" | "-h" | "-z" | "-n"
+        );
+        if is_unary && self.peek().is_some_and(|t| !matches!(t, "&&" | "||" | ")")) {
+            let operand = self.next()?;
+            return Ok(eval && unary_test(token, &expand_word(operand)));
+        }
+        let op = match self.peek() {
+            Some(
+                op @ ("==" | "=" | "!=" | "=~" | "<" | ">" | "-eq" | "-ne" | "-lt" | "-le" | "-gt"
+                | "-ge"),
+            ) => op,
+            // 単語一つだけなら空でないかを調べる
+            _ => return Ok(eval && !expand_word(token).is_empty()),
+        };
+        self.pos += 1;
+        let right = self.next()?;
+        if !eval {
+            return Ok(false);
+        }
+        let left = expand_word(token);
+        match op {
+            "==" | "=" => Ok(glob_match(&expand_word_parts(right, false).pattern, &left, false)),
+            "!=" => Ok(!glob_match(&expand_word_parts(right, false).pattern, &left, false)),
+            "=~" => {
+                let re = regex::Regex::new(&expand_word(right)).map_err(|e| e.to_string())?;
+                Ok(re.is_match(&left))
+            }
+            "<" => Ok(left < expand_word(right)),
+            ">" => Ok(left > expand_word(right)),
+            _ => {
+                let number = |s: &str| {
+                    s.trim().parse::<i64>().map_err(|_| format!("{s}: 整数が必要です"))
+                };
+                let (a, b) = (number(&left)?, number(&expand_word(right))?);
+                Ok(match op {
+                    "-eq" => a == b,
+                    "-ne" => a != b,
+                    "-lt" => a < b,
+                    "-le" => a <= b,
+                    "-gt" => a > b,
+                    _ => a >= b,
+                })
+            }
+        }
+    }
+}
+
+// 算術式を評価する。整数 (`0x` の 16 進と `0` 始まりの 8 進を含む)、変数名、括弧と
+// C と同じ優先順位の単項・二項演算子、`?:`、`,` に対応する。あふれは折り返す
+fn eval_arith(expr: &str) -> Result<i64, String> {
+    let mut parser = ArithParser { tokens: tokenize_arith(expr)?, pos: 0 };
+    if parser.tokens.is_empty() {
+        return Ok(0);
+    }
+    let value = parser.comma(true)?;
+    match parser.tokens.get(parser.pos) {
+        Some(token) => Err(format!("`{token}` の近くに構文エラーがあります")),
+        None => Ok(value),
+    }
+}
+
+const ARITH_OPS: &[&str] = &[
+    "<<=", ">>=", "**", "<<", ">>", "<=", ">=", "==", "!=", "&&", "||", "++", "--", "+=", "-=",
+    "*=", "/=", "%=", "&=", "|=", "^=", "+", "-", "*", "/", "%", "<", ">", "!", "~", "&", "|",
+    "^", "?", ":", "=", ",", "(", ")",
+];
+
+// 数値と変数名はそのまま、演算子は ARITH_OPS の最長一致で区切る
+fn tokenize_arith(expr: &str) -> Result<Vec<String>, String> {
+    let mut tokens = Vec::new();
+    let mut rest = expr.trim_start();
+    while let Some(c) = rest.chars().next() {
+        let len = if c.is_ascii_alphanumeric() || c == '_' {
+            rest.find(|c: char| !(c.is_ascii_alphanumeric() || c == '_')).unwrap_or(rest.len())
+        } else if let Some(op) = ARITH_OPS.iter().find(|op| rest.starts_with(**op)) {
+            op.len()
+        } else {
+            return Err(format!("`{c}` は使えない文字です"));
+        };
+        tokens.push(rest[..len].to_string());
+        rest = rest[len..].trim_start();
+    }
+    Ok(tokens)
+}
+
+// eval が false の部分は `&&` `||` `?:` で評価されない側なので、構文だけ確かめる
+struct ArithParser {
+    tokens: Vec<String>,
+    pos: usize,
+}
+
+impl ArithParser {
+    fn peek(&self) -> Option<&str> {
+        self.tokens.get(self.pos).map(String::as_str)
+    }
+
+    fn next(&mut self) -> Result<String, String> {
+        let token = self.tokens.get(self.pos).cloned().ok_or("式が途中で終わっています")?;
+        self.pos += 1;
+        Ok(token)
+    }
+
+    fn expect(&mut self, expected: &str) -> Result<(), String> {
+        match self.next()? {
+            token if token == expected => Ok(()),
+            token => Err(format!("`{expected}` が必要ですが `{token}` がありました")),
+        }
+    }
+
+    fn comma(&mut self, eval: bool) -> Result<i64, String> {
+        let mut value = self.assign(eval)?;
+        while self.peek() == Some(",") {
+            self.pos += 1;
+            value = self.assign(eval)?;
+        }
+        Ok(value)
+    }
+
+    // `x = ...` `x += ...` などの代入は右結合で、結果を変数に書き戻す
+    fn assign(&mut self, eval: bool) -> Result<i64, String> {
+        let op = self.tokens.get(self.pos + 1).map(String::as_str);
+        let is_assign = matches!(
+            op,
+            Some("=" | "+=" | "-=" | "*=" | "/=" | "%=" | "<<=" | ">>=" | "&=" | "|=" | "^=")
+        );
+        if !is_assign || !self.peek().is_some_and(is_arith_name) {
+            return self.ternary(eval);
+        }
+        let name = self.next()?;
+        let op = self.next()?;
+        let right = self.assign(eval)?;
+        if !eval {
+            return Ok(0);
+        }
+        let value = match op.strip_suffix('=').filter(|o| !o.is_empty()) {
+            Some(binary) => apply_arith(binary, arith_variable(&name)?, right)?,
+            None => right,
+        };
+        set_arith_variable(&name, value);
+        Ok(value)
+    }
+
+    fn ternary(&mut self, eval: bool) -> Result<i64, String> {
+        let cond = self.binary(0, eval)?;
+        if self.peek() != Some("?") {
+            return Ok(cond);
+        }
+        self.pos += 1;
+        let then = self.comma(eval && cond != 0)?;
+        self.expect(":")?;
+        let otherwise = self.ternary(eval && cond == 0)?;
+        Ok(if cond != 0 { then } else { otherwise })
+    }
+
+    // 優先順位が min 以上の二項演算子だけを読む (優先順位法)
+    fn binary(&mut self, min: u8, eval: bool) -> Result<i64, String> {
+        let mut left = self.unary(eval)?;
+        while let Some(op) = self.peek().map(str::to_string) {
+            let Some(prec) = arith_precedence(&op).filter(|&p| p >= min) else {
+                break;
+            };
+            self.pos += 1;
+            // `**` だけは右結合
+            let next_min = if op == "**" { prec } else { prec + 1 };
+            let right = match op.as_str() {
+                "&&" => self.binary(next_min, eval && left != 0)?,
+                "||" => self.binary(next_min, eval && left == 0)?,
+                _ => self.binary(next_min, eval)?,
+            };
+            if eval {
+                left = apply_arith(&op, left, right)?;
+            }
+        }
+        Ok(left)
+    }
+
+    fn unary(&mut self, eval: bool) -> Result<i64, String> {
+        // 前置の `++x` / `--x`。変数が続かなければ符号二つ (`--1` は 1) として読む
+        if let Some(op @ ("++" | "--")) = self.peek() {
+            let delta = if op == "++" { 1 } else { -1 };
+            if let Some(name) = self.tokens.get(self.pos + 1).filter(|t| is_arith_name(t)) {
+                let name = name.clone();
+                self.pos += 2;
+                if !eval {
+                    return Ok(0);
+                }
+                let value = arith_variable(&name)?.wrapping_add(delta);
+                set_arith_variable(&name, value);
+                return Ok(value);
+            }
+            self.pos += 1;
+            return self.unary(eval);
+        }
+        match self.peek() {
+            Some("-") => {
+                self.pos += 1;
+                Ok(self.unary(eval)?.wrapping_neg())
+            }
+            Some("+") => {
+                self.pos += 1;
+                self.unary(eval)
+            }
+            Some("!") => {
+                self.pos += 1;
+                Ok((self.unary(eval)? == 0) as i64)
+            }
+            Some("~") => {
+                self.pos += 1;
+                Ok(!self.unary(eval)?)
+            }
+            _ => self.primary(eval),
+        }
+    }
+
+    fn primary(&mut self, eval: bool) -> Result<i64, String> {
+        let token = self.next()?;
+        if token == "(" {
+            let value = self.comma(eval)?;
+            self.expect(")")?;
+            return Ok(value);
+        }
+        if token.starts_with(|c: char| c.is_ascii_digit()) {
+            return parse_arith_number(&token);
+        }
+        if is_arith_name(&token) {
+            // 後置の `x++` / `x--` は変更前の値を返す
+            if let Some(op @ ("++" | "--")) = self.peek() {
+                let delta = if op == "++" { 1 } else { -1 };
+                self.pos += 1;
+                if !eval {
+                    return Ok(0);
+                }
+                let value = arith_variable(&token)?;
+                set_arith_variable(&token, value.wrapping_add(delta));
+                return Ok(value);
+            }
+            return if eval { arith_variable(&token) } else { Ok(0) };
+        }
+        Err(format!("`{token}` の近くに構文エラーがあります"))
+    }
+}
+
+fn is_arith_name(token: &str) -> bool {
+    token.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_')
+}
+
+fn set_arith_variable(name: &str, value: i64) {
+    unsafe { env::set_var(name, value.to_string()) };
+}
+
+fn arith_precedence(op: &str) -> Option<u8> {
+    Some(match op {
+        "||" => 0,
+        "&&" => 1,
+        "|" => 2,
+        "^" => 3,
+        "&" => 4,
+        "==" | "!=" => 5,
+        "<" | "<=" | ">" | ">=" => 6,
+        "<<" | ">>" => 7,
+        "+" | "-" => 8,
+        "*" | "/" | "%" => 9,
+        "**" => 10,
+        _ => return None,
+    })
+}
+
+fn apply_arith(op: &str, a: i64, b: i64) -> Result<i64, String> {
+    Ok(match op {
+        "||" => (a != 0 || b != 0) as i64,
+        "&&" => (a != 0 && b != 0) as i64,
+        "|" => a | b,
+        "^" => a ^ b,
+        "&" => a & b,
+        "==" => (a == b) as i64,
+        "!=" => (a != b) as i64,
+        "<" => (a < b) as i64,
+        "<=" => (a <= b) as i64,
+        ">" => (a > b) as i64,
+        ">=" => (a >= b) as i64,
+        "<<" => a.wrapping_shl(b as u32),
+        ">>" => a.wrapping_shr(b as u32),
+        "+" => a.wrapping_add(b),
+        "-" => a.wrapping_sub(b),
+        "*" => a.wrapping_mul(b),
+        "/" | "%" if b == 0 => return Err("0 で割ることはできません".to_string()),
+        "/" => a.wrapping_div(b),
+        "%" => a.wrapping_rem(b),
+        "**" if b < 0 => return Err("指数が負です".to_string()),
+        "**" => a.wrapping_pow(b.min(u32::MAX as i64) as u32),
+        _ => return Err(format!("`{op}` は使えない演算子です")),
+    })
+}
+
+fn parse_arith_number(token: &str) -> Result<i64, String> {
+    let parsed = if let Some(hex) = token.strip_prefix("0x").or_else(|| token.strip_prefix("0X")) {
+        i64::from_str_radix(hex, 16)
+    } else if token.len() > 1 && token.starts_with('0') {
+        i64::from_str_radix(&token[1..], 8)
+    } else {
+        token.parse()
+    };
+    parsed.map_err(|_| format!("{token}: 数値として不正です"))
+}
+
+// 変数の値。未設定や空なら 0
+fn arith_variable(name: &str) -> Result<i64, String> {
+    let value = env::var(name).unwrap_or_default();
+    let value = value.trim();
+    if value.is_empty() {
+        return Ok(0);
+    }
+    let (negative, digits) = match value.strip_prefix('-') {
+        Some(digits) => (true, digits),
+        None => (false, value.strip_prefix('+').unwrap_or(value)),
+    };
+    let number = parse_arith_number(digits).map_err(|_| format!("{name}: 値 `{value}` は整数ではありません"))?;
+    Ok(if negative { number.wrapping_neg() } else { number })
+}
+
+fn unary_test(op: &str, operand: &str) -> bool {
+    let path = Path::new(operand);
+    let access = |mode| {
+        CString::new(operand).is_ok_and(|p| unsafe { libc::access(p.as_ptr(), mode) } == 0)
+    };
+    match op {
+        "-e" => path.exists(),
+        "-f" => path.is_file(),
+        "-d" => path.is_dir(),
+        "-s" => fs::metadata(path).is_ok_and(|m| m.len() > 0),
+        "-r" => access(libc::R_OK),
+        "-w" => access(libc::W_OK),
+        "-x" => access(libc::X_OK),
+        "-L" | "-h" => path.is_symlink(),
+        "-z" => operand.is_empty(),
+        _ => !operand.is_empty(), // -n
+    }
+}
+
+// コマンドの先頭にあるエイリアスを、その値を parse_line で字句解析し直したトークンで置き換える。
+// 値の中のパイプやリダイレクションも演算子として働く。展開の途中で同じ名前に戻ってきたら
+// それ以上は展開せず、値が空白で終わるときは次の単語もエイリアスとして調べる
+fn expand_aliases(
+    tokens: Vec<String>,
+    aliases: &HashMap<String, String>,
+) -> Result<Vec<String>, ParseError> {
+    if aliases.is_empty() {
+        return Ok(tokens);
+    }
+    // (トークン, そのトークンを生んだエイリアス名, 先頭でなくてもエイリアスとして調べるか)
+    let mut stack: Vec<(String, Vec<String>, bool)> =
+        tokens.into_iter().rev().map(|t| (t, Vec::new(), false)).collect();
+    let mut out = Vec::new();
+    let mut at_command = true;
+    while let Some((token, active, check)) = stack.pop() {
+        if (at_command || check)
+            && !active.contains(&token)
+            && let Some(value) = aliases.get(&token)
+        {
+            if value.ends_with([' ', '\t'])
+                && let Some(next) = stack.last_mut()
+            {
+                next.2 = true;
+            }
+            let mut active = active;
+            active.push(token);
+            for t in parse_line(value)?.into_iter().rev() {
+                stack.push((t, active.clone(), false));
+            }
+            continue;
+        }
+        at_command = matches!(token.as_str(), "|" | ";" | "(" | "&" | "&&" | "||");
+        out.push(token);
+    }
+    Ok(out)
+}
+
+// 閉じていないクォートや括弧、行末のパイプや `&&` / `||`、バックスラッシュがあれば入力はまだ続く
+fn is_incomplete(input: &str) -> bool {
+    if ends_with_continuation(input) {
+        return true;
+    }
+    let tokens = match parse_line(input) {
+        Ok(tokens) => tokens,
+        Err(e) => return e.is_incomplete(),
+    };
+    let depth = tokens.iter().fold(0, |depth, t| match t.as_str() {
+        "(" => depth + 1,
+        ")" => depth - 1,
+        _ => depth,
+    });
+    // 複合コマンドの途中の改行は split_script_lines が `;` に直すので、その結果の最後の行で確かめる
+    let open_compound = split_script_lines(input)
+        .last()
+        .and_then(|line| parse_line(line).ok())
+        .is_some_and(|tokens| in_compound(&tokens));
+    depth > 0
+        || open_compound
+        || tokens.last().is_some_and(|t| matches!(t.as_str(), "|" | "&&" | "||"))
+}
+
+// split_script_lines が読み進めた、閉じていない複合コマンドの深さ。
+// last は直前のトークンと、それがコマンドの位置にあったか
+#[derive(Default)]
+struct CompoundTracker {
+    depth: usize,
+    last: Option<(String, bool)>,
+}
+
+impl CompoundTracker {
+    // in_compound と同じ数え方で、続きのトークンの分だけ進める
+    fn feed(&mut self, tokens: Vec<String>) {
+        for token in tokens {
+            let at_command = self.last.as_ref().is_none_or(|(prev, at)| starts_command_after(prev, *at));
+            if at_command && COMPOUND_OPENERS.contains(&token.as_str()) {
+                self.depth += 1;
+            } else if at_command && COMPOUND_CLOSERS.contains(&token.as_str()) {
+                self.depth = self.depth.saturating_sub(1);
+            }
+            self.last = Some((token, at_command));
+        }
+    }
+
+    // `do` などの直後の改行は空白でよい (`echo do` の `do` は予約語ではないので数えない)
+    fn joins(&self) -> bool {
+        self.last.as_ref().is_some_and(|(prev, at)| starts_command_after(prev, *at))
+    }
+}
+
+// クォートや `$(...)` の外にある改行でスクリプトを行に分ける。
+// parse_line に通すのは、前の改行から後に読んだ部分 (current[start..]) だけにする
+fn split_script_lines(script: &str) -> Vec<String> {
+    let mut lines = Vec::new();
+    let mut current = String::new();
+    let mut start = 0;
+    let mut compound = CompoundTracker::default();
+    let mut depth = 0;
+    let mut chars = script.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            // 行末の `|` や `&&` の後の改行は空白、`( ... )` の途中の改行は `;` として扱う
+            '\n' if current.trim_end().ends_with('|') || current.trim_end().ends_with("&&") => {
+                current.push(c)
+            }
+            '\n' if depth > 0 => current.push(';'),
+            '\n' => {
+                let in_compound = match parse_line(&current[start..]) {
+                    Ok(tokens) => {
+                        compound.feed(tokens);
+                        compound.depth > 0
+                    }
+                    // ヒアドキュメントの本文は終わりの行まで手を付けずにそのまま抱えておく。
+                    // 読み直すのは終わりの行と同じ内容の行が来たときだけ
+                    Err(ParseError::UnclosedHeredoc { mut delimiter }) => {
+                        current.push(c);
+                        while chars.peek().is_some() {
+                            let line: String = chars.by_ref().take_while(|&c| c != '\n').collect();
+                            current.push_str(&line);
+                            current.push('\n');
+                            if line.trim_start_matches('\t') != delimiter {
+                                continue;
+                            }
+                            match parse_line(&current[start..]) {
+                                Err(ParseError::UnclosedHeredoc { delimiter: next }) => delimiter = next,
+                                _ => break,
+                            }
+                        }
+                        current.pop();
+                        false
+                    }
+                    Err(_) => false,
+                };
+                // 閉じていない `for ... done` や `if ... fi` などの中の改行は、`do` などの直後なら空白、コマンドの後なら `;`
+                if in_compound {
+                    if compound.joins() {
+                        current.push(' ');
+                    } else {
+                        current.push(';');
+                        compound.feed(vec![";".to_string()]);
+                    }
+                    start = current.len();
+                } else {
+                    lines.push(std::mem::take(&mut current));
+                    start = 0;
+                    compound = CompoundTracker::default();
+                }
+            }
+            // 単語の先頭の `#` から行末まではコメント
+            '#' if current.is_empty() || current.ends_with([' ', '\t', ';', '&', '|', '(']) => {
+                while chars.next_if(|&c| c != '\n').is_some() {}
+            }
+            '(' | ')' => {
+                depth += if c == '(' { 1 } else { -1 };
+                current.push(c);
+            }
+            // 閉じていないクォートは残りをそのまま抱えておき、parse_line にエラーを報告させる
+            '\'' | '"' | '`' => {
+                let _ = read_quoted(&mut chars, c, 0, &mut current);
+            }
+            '\\' => {
+                current.push(c);
+                if let Some(n) = chars.next() {
+                    current.push(n);
+                }
+            }
+            '$' if matches!(chars.peek(), Some('(') | Some('{')) => {
+                let open = chars.next().unwrap();
+                current.push(c);
+                current.push(open);
+                let _ = read_group(&mut chars, open, 0, &mut current);
+            }
+            _ => current.push(c),
+        }
+    }
+    lines.push(current);
+    lines
+}
+
+// 行末がエスケープされていないバックスラッシュなら次の行に続く
+fn ends_with_continuation(line: &str) -> bool {
+    line.chars().rev().take_while(|&c| c == '\\').count() % 2 == 1
+}
+
+// スクリプトを一行ずつ実行する。`break` / `continue` で抜けてきたらそこで止めてその流れを返す
+fn run_script(script: &str, state: &mut ShellState) -> rustyline::Result<Flow> {
+    // `;` での区切りは run_line がクォートを見ながら行う
+    let mut flow = Flow::Normal(0);
+    for part in split_script_lines(script) {
+        let trimmed = part.trim();
+        if trimmed.is_empty() {
+            continue;
+        }
+        flow = run_line(trimmed, state);
+        if state.errexit_tripped || flow != Flow::Normal(flow.status()) {
+            break;
+        }
+    }
+    Ok(flow)
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // テストごとに空のディレクトリを作る
+    fn scratch_dir(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("unko_test_{}_{name}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    // ビルド済みの unko (target/debug/deps/ から見た target/debug/unko)
+    fn unko() -> Command {
+        let exe = env::current_exe().unwrap();
+        let mut cmd = Command::new(exe.parent().and_then(Path::parent).unwrap().join("unko"));
+        cmd.stdin(Stdio::null());
+        cmd
+    }
+
+    // `unko -c SCRIPT` を実行する
+    fn run_unko(script: &str) -> std::process::Output {
+        unko().arg("-c").arg(script).output().unwrap()
+    }
+
+    fn status_of(script: &str) -> i32 {
+        run_unko(script).status.code().unwrap()
+    }
+
+    #[test]
+    fn glob_matches_sorted_and_keeps_unmatched_pattern() {
+        let dir = scratch_dir("glob");
+        for name in ["b.rs", "a.rs", "c.txt", "x1", "y1", ".hidden.rs"] {
+            fs::write(dir.join(name), "").unwrap();
+        }
+        let prefix = format!("{}/", dir.display());
+        let glob = |pattern: &str| -> Vec<String> {
+            expand_arg(&format!("{prefix}{pattern}"), &ShellOptions::default())
+                .into_iter()
+                .map(|path| path.strip_prefix(&prefix).unwrap().to_string())
+                .collect()
+        };
+        assert_eq!(glob("*.rs"), ["a.rs", "b.rs"]);
+        assert_eq!(glob("?1"), ["x1", "y1"]);
+        assert_eq!(glob("[a-z].rs"), ["a.rs", "b.rs"]);
+        assert_eq!(glob("[!x]1"), ["y1"]);
+        assert_eq!(glob("[[:alpha:]].txt"), ["c.txt"]);
+        assert_eq!(glob(".*.rs"), [".hidden.rs"]);
+        assert_eq!(glob("*.py"), ["*.py"]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn completion_finds_the_command_under_the_cursor() {
+        let cases = [
+            ("cd sr", Some("cd")),
+            ("ls; cd ", Some("cd")),
+            ("cd x && ls ", Some("ls")),
+            ("false || cd ", Some("cd")),
+            ("cat a | cd ", Some("cd")),
+            ("ls; ", None),
+        ];
+        for (line, word) in cases {
+            assert_eq!(command_word_at(line, line.len()), word, "{line}");
+        }
+    }
+
+    #[test]
+    fn quoted_semicolons_do_not_separate_commands() {
+        let lists = |line: &str| -> Vec<Vec<String>> {
+            let tokens = parse_line(line).unwrap();
+            split_lists(&tokens).unwrap().iter().map(|list| list.to_vec()).collect()
+        };
+        assert_eq!(lists("echo \"a;b\"; echo 'a;b'"), [vec!["echo", "\"a;b\""], vec!["echo", "'a;b'"]]);
+        assert_eq!(lists("echo \"a;'b\"';\"c' x\\;y;"), [vec!["echo", "\"a;'b\"';\"c'", "x\\;y"]]);
+        assert_eq!(split_script_lines("echo 'a;b'\necho \"c;d\""), ["echo 'a;b'", "echo \"c;d\""]);
+        let out = run_unko("echo \"a;b\"; echo 'a;b'; echo \"a;'b\"';\"c'; echo x\\;y");
+        assert_eq!(String::from_utf8_lossy(&out.stdout), "a;b\na;b\na;'b;\"c\nx;y\n");
+    }
+
+    #[test]
+    fn script_lines_join_compound_commands() {
+        let script = "for i in 1 2\ndo\n  echo do\n  echo $i\ndone\nif false; then\n  :\nelse echo elif\nfi\necho end";
+        assert_eq!(
+            split_script_lines(script),
+            ["for i in 1 2;do   echo do;  echo $i;done", "if false; then   :;else echo elif;fi", "echo end"]
+        );
+    }
+
+    #[test]
+    fn pipeline_status_follows_pipefail() {
+        assert_eq!(status_of("false | true"), 0);
+        assert_eq!(status_of("true | false"), 1);
+        assert_eq!(status_of("set -o pipefail; false | true"), 1);
+        assert_eq!(status_of("set -o pipefail; true | false"), 1);
+        assert_eq!(status_of("set -o pipefail; true | true"), 0);
+        assert_eq!(status_of("set -o pipefail; (exit 3) | true | true"), 3);
+        assert_eq!(status_of("set -o pipefail; set +o pipefail; false | true"), 0);
+    }
+
+    #[test]
+    fn while_and_until_count_iterations() {
+        let out = run_unko("i=0; while [ $i -lt 5 ]; do i=$((i + 1)); done; echo $i");
+        assert_eq!(String::from_utf8_lossy(&out.stdout), "5\n");
+        let out = run_unko("i=3; until [ $i -eq 0 ]; do echo $i; i=$((i - 1)); done");
+        assert_eq!(String::from_utf8_lossy(&out.stdout), "3\n2\n1\n");
+        // 条件が最初から偽なら本体は一度も実行しない
+        let out = run_unko("while false; do echo x; done; until true; do echo y; done; echo end");
+        assert_eq!(String::from_utf8_lossy(&out.stdout), "end\n");
+    }
+
+    #[test]
+    fn parse_line_drops_comments() {
+        assert_eq!(parse_line("# only a comment").unwrap(), Vec::<String>::new());
+        assert_eq!(parse_line("echo hi # this is a comment").unwrap(), ["echo", "hi"]);
+        assert_eq!(parse_line("echo hi;# c").unwrap(), ["echo", "hi", ";"]);
+        assert_eq!(parse_line("echo foo#bar").unwrap(), ["echo", "foo#bar"]);
+        assert_eq!(parse_line("echo '# a' \"# b\" \\#c").unwrap(), ["echo", "'# a'", "\"# b\"", "\\#c"]);
+    }
+
+    #[test]
+    fn unquoted_expansion_splits_on_ifs() {
+        let dir = scratch_dir("ifs");
+        let files = |script: &str| -> Vec<String> {
+            for entry in fs::read_dir(&dir).unwrap() {
+                fs::remove_file(entry.unwrap().path()).unwrap();
+            }
+            assert!(run_unko(&format!("cd {}; {script}", dir.display())).status.success());
+            let mut names: Vec<String> =
+                fs::read_dir(&dir).unwrap().map(|e| e.unwrap().file_name().to_string_lossy().into_owned()).collect();
+            names.sort();
+            names
+        };
+        assert_eq!(files("X='a b'; touch $X"), ["a", "b"]);
+        assert_eq!(files("X='a b'; touch \"$X\""), ["a b"]);
+        assert_eq!(files("IFS=:; X=c:d; touch $X"), ["c", "d"]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn type_reports_names_in_argument_order() {
+        let out = run_unko("type cd unko_no_such_cmd echo unko_no_such_cmd2 2>&1");
+        assert_eq!(
+            String::from_utf8_lossy(&out.stdout),
+            "cd is a shell builtin\ntype: unko_no_such_cmd: 見つかりません\n\
+             echo is a shell builtin\ntype: unko_no_such_cmd2: 見つかりません\n"
+        );
+        assert_eq!(out.status.code(), Some(1));
+    }
+
+    #[test]
+    fn history_from_two_sessions_is_merged_in_order() {
+        let dir = scratch_dir("history");
+        let path = dir.join("history");
+        let entry = |line: &str, time: Option<i64>| HistoryEntry { line: line.to_string(), time };
+        // 二つのシェルが交互に同じファイルへ追記する
+        let appended = [
+            entry("echo one", None),
+            entry("#123", None),
+            entry("echo two", Some(1700000000)),
+            entry("#456", Some(1700000001)),
+            entry("printf 'a\\nb'\necho three", None),
+        ];
+        for entry in &appended {
+            append_history_file(&path, entry).unwrap();
+        }
+        let lines = |entries: &[HistoryEntry]| -> Vec<(String, Option<i64>)> {
+            entries.iter().map(|e| (e.line.clone(), e.time)).collect()
+        };
+        let loaded = load_history_file(&path);
+        assert_eq!(lines(&loaded), lines(&appended));
+        // 終了時の切り詰めで書き直しても変わらない
+        write_history_file(&path, &loaded[1..]).unwrap();
+        assert_eq!(lines(&load_history_file(&path)), lines(&appended[1..]));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn dash_c_is_accepted_after_other_options() {
+        for args in [&["-c"][..], &["--norc", "-c"], &["--rcfile", "/nonexistent", "-c"]] {
+            let out = unko().args(args).args(["echo $0 $1", "name", "-x"]).output().unwrap();
+            assert_eq!(String::from_utf8_lossy(&out.stdout), "name -x\n", "{args:?}");
+        }
+        let out = unko().args(["--norc", "-c"]).output().unwrap();
+        assert_eq!(out.status.code(), Some(2));
+        assert_eq!(String::from_utf8_lossy(&out.stderr), "unko: -c にはスクリプトが必要です\n");
+    }
+
+    #[test]
+    fn process_substitution_removes_its_fifo() {
+        let dir = scratch_dir("procsub_fifo");
+        let scripts = [
+            "echo hi | tee >(cat >/dev/null); cat <(echo hi)",
+            // コマンドが見つからない、リダイレクトに失敗する、FIFO を開かずに終わる
+            "unko_no_such_cmd <(echo hi)",
+            "cat <(echo hi) >/nonexistent/dir/file",
+            "true <(echo hi) >(cat)",
+        ];
+        for script in scripts {
+            unko().env("TMPDIR", &dir).arg("-c").arg(script).output().unwrap();
+            let left: Vec<_> = fs::read_dir(&dir).unwrap().map(|e| e.unwrap().file_name()).collect();
+            assert!(left.is_empty(), "{script}: {left:?}");
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn process_substitution_streams_megabytes() {
+        let out = run_unko("wc -c <(head -c 5000000 /dev/zero)");
+        let count = String::from_utf8_lossy(&out.stdout);
+        assert_eq!(count.split_whitespace().next(), Some("5000000"));
+        let out = run_unko("head -c 5000000 /dev/zero | tee >(wc -c >&2) | wc -c");
+        assert_eq!(String::from_utf8_lossy(&out.stdout).trim(), "5000000");
+        assert_eq!(String::from_utf8_lossy(&out.stderr).trim(), "5000000");
+    }
+
+    #[test]
+    fn missing_and_non_executable_commands() {
+        let dir = scratch_dir("exec_status");
+        let script = dir.join("not_executable.sh");
+        fs::write(&script, "echo hi\n").unwrap();
+        assert_eq!(status_of("unko_no_such_cmd"), 127);
+        assert_eq!(status_of(&format!("{}/unko_no_such_cmd", dir.display())), 127);
+        assert_eq!(status_of(&script.to_string_lossy()), 126);
+        assert_eq!(status_of(&dir.to_string_lossy()), 126);
+        assert_eq!(String::from_utf8_lossy(&run_unko("unko_no_such_cmd; echo $?").stdout), "127\n");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn loop_stage_reads_share_one_stream() {
+        let out = run_unko("printf '1\\n2\\n3\\n4\\n' | while read a; do read b; echo \"$a-$b\"; done");
+        assert_eq!(String::from_utf8_lossy(&out.stdout), "1-2\n3-4\n");
+    }
+
+    #[test]
+    fn command_substitution_inherits_status_and_options() {
+        let stdout = |script: &str| String::from_utf8_lossy(&run_unko(script).stdout).into_owned();
+        assert_eq!(stdout("false; echo $(echo $?)"), "1\n");
+        assert_eq!(stdout("set -o noglob; echo $(echo /*)"), "/*\n");
+        assert_eq!(stdout("echo \"[$(printf 'a\\377b')]\""), "[a\u{FFFD}b]\n");
+        let out = run_unko("set -o strictutf8; echo \"[$(printf 'a\\377b')]\"");
+        assert_eq!(String::from_utf8_lossy(&out.stdout), "[]\n");
+        assert!(String::from_utf8_lossy(&out.stderr).contains("UTF-8 として不正"));
+    }
+
+    #[test]
+    fn nounset_stops_commands_that_expand_unset_variables() {
+        let out = run_unko("set -u; echo \"[$UNKO_UNDEF]\"; echo $?; echo ${UNKO_UNDEF:-default}; cd $UNKO_UNDEF; echo $?");
+        assert_eq!(String::from_utf8_lossy(&out.stdout), "1\ndefault\n1\n");
+        assert_eq!(String::from_utf8_lossy(&out.stderr).matches("UNKO_UNDEF: 未設定の変数です").count(), 2);
+        let out = run_unko("set -o nounset; set +u; echo \"[$UNKO_UNDEF]\"");
+        assert_eq!(String::from_utf8_lossy(&out.stdout), "[]\n");
+        let out = run_unko("set -uC; set +o");
+        let stdout = String::from_utf8_lossy(&out.stdout);
+        assert!(stdout.contains("set -o noclobber\n") && stdout.contains("set -o nounset\n"), "{stdout}");
+    }
+
+    #[test]
+    fn export_p_writes_a_sourceable_snapshot() {
+        let dir = scratch_dir("export_p");
+        let rc = dir.join("rc");
+        let out = run_unko(&format!(
+            "alias ll='ls -l'; export UNKO_SNAPSHOT=\"it's\"; export -p > {0}; export -p | grep -c '^alias ll='",
+            rc.display()
+        ));
+        assert_eq!(String::from_utf8_lossy(&out.stdout), "1\n");
+        let out = unko()
+            .env_remove("UNKO_SNAPSHOT")
+            .arg("-c")
+            .arg(format!("source {}\necho \"$UNKO_SNAPSHOT\"; alias ll", rc.display()))
+            .output()
+            .unwrap();
+        assert_eq!(String::from_utf8_lossy(&out.stdout), "it's\nalias ll='ls -l'\n");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn command_completion_offers_dotfiles_only_for_a_dot_prefix() {
+        use std::os::unix::fs::PermissionsExt;
+        let dir = scratch_dir("dot_exe");
+        for name in ["run_me", ".hidden_tool", "plain.txt"] {
+            fs::write(dir.join(name), "").unwrap();
+        }
+        for name in ["run_me", ".hidden_tool"] {
+            fs::set_permissions(dir.join(name), fs::Permissions::from_mode(0o755)).unwrap();
+        }
+        let offered = |prefix: &str| -> Vec<String> {
+            let mut names: Vec<String> =
+                executables_in(&dir).into_iter().filter(|name| completes_name(name, prefix)).collect();
+            names.sort();
+            names
+        };
+        assert_eq!(offered(""), ["run_me"]);
+        assert_eq!(offered("r"), ["run_me"]);
+        assert_eq!(offered("."), [".hidden_tool"]);
+        assert_eq!(offered(".h"), [".hidden_tool"]);
+        assert!(offered("h").is_empty());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn tilde_expands_home_directories() {
+        let out = unko()
+            .env("HOME", "/home/unko_tester")
+            .arg("-c")
+            .arg("echo ~ ~/foo ~root ~root/bin ~unko_no_such_user/x '~' x~")
+            .output()
+            .unwrap();
+        assert_eq!(
+            String::from_utf8_lossy(&out.stdout),
+            "/home/unko_tester /home/unko_tester/foo /root /root/bin ~unko_no_such_user/x ~ x~\n"
+        );
+    }
+
+    #[test]
+    fn quoted_empty_strings_are_arguments() {
+        let argv = |script: &str| String::from_utf8_lossy(&run_unko(script).stdout).into_owned();
+        assert_eq!(argv("printf '[%s]' \"\" x"), "[][x]");
+        assert_eq!(argv("printf '[%s]' x '' \"\""), "[x][][]");
+        assert_eq!(argv("E=; printf '[%s]' \"$E\" $E x"), "[][x]");
+        assert_eq!(argv("sh -c 'echo $#' sh \"\" '' x"), "3\n");
+    }
+
+    #[test]
+    fn rehash_picks_up_new_executables_for_completion() {
+        use std::os::unix::fs::PermissionsExt;
+        let dir = scratch_dir("rehash");
+        let name = format!("unko_rehash_{}", std::process::id());
+        let complete = |line: &str| -> Vec<String> {
+            let helper = ShellHelper {
+                completer: FilenameCompleter::new(),
+                highlighter: MatchingBracketHighlighter::new(),
+                history: VecDeque::new(),
+                aliases: HashSet::new(),
+            };
+            let history = rustyline::history::DefaultHistory::new();
+            let (_, pairs) = helper.complete(line, line.len(), &Context::new(&history)).unwrap();
+            pairs.into_iter().map(|pair| pair.replacement).collect()
+        };
+        // 作る前の PATH で一覧を作っておく
+        Lazy::force(&BIN_CACHE);
+        let old_path = env::var_os("PATH").unwrap_or_default();
+        let mut path = dir.clone().into_os_string();
+        path.push(":");
+        path.push(&old_path);
+        unsafe { env::set_var("PATH", &path) };
+        let exe = dir.join(&name);
+        fs::write(&exe, "#!/bin/sh\n").unwrap();
+        fs::set_permissions(&exe, fs::Permissions::from_mode(0o755)).unwrap();
+        assert!(complete(&name).is_empty());
+        assert_eq!(try_builtin_special(&["rehash".to_string()], &mut ShellState::default()), Some(0));
+        assert_eq!(complete(&name), [name.as_str()]);
+        unsafe { env::set_var("PATH", &old_path) };
+        *BIN_CACHE.write().unwrap() = scan_path_binaries();
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn type_reports_keywords() {
+        let out = run_unko("type for while then fi '[['; command -v if");
+        assert_eq!(
+            String::from_utf8_lossy(&out.stdout),
+            "for is a shell keyword\nwhile is a shell keyword\nthen is a shell keyword\n\
+             fi is a shell keyword\n[[ is a shell keyword\nif\n"
+        );
+        assert!(out.status.success());
+    }
+
+    #[test]
+    fn positional_parameters_reach_substitutions_and_set() {
+        let out = unko()
+            .args(["-c", "echo $(echo in:$0:$1:$#); set -- x 'y z'; set -- \"$@\" w; printf '[%s]' $# \"$@\"", "nm", "a"])
+            .output()
+            .unwrap();
+        assert_eq!(String::from_utf8_lossy(&out.stdout), "in:nm:a:1\n[3][x][y z][w]");
+        assert_eq!(String::from_utf8_lossy(&run_unko("set -- a b; set --; echo $#").stdout), "0\n");
+    }
+
+    #[test]
+    fn stderr_append_keeps_earlier_output() {
+        let dir = scratch_dir("stderr_append");
+        let out = run_unko(&format!(
+            "cd {}; echo first > log; sh -c 'echo e1 >&2' 2>> log; sh -c 'echo e2 >&2' 2>>log; \
+             sh -c 'echo out; echo e3 >&2' >> log 2>&1; sh -c 'echo e4 >&2' 2>> log 2>&1; cat log",
+            dir.display()
+        ));
+        assert_eq!(String::from_utf8_lossy(&out.stdout), "e4\nfirst\ne1\ne2\nout\ne3\n");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}